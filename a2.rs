@@ -1,6 +1,11 @@
 
 use mem::Mem;
+use cpu::{Cpu, Cycles, StopReason, CpuVariant, Nmos6502, Cmos65C02};
+use state::{Snapshot, StateWriter, StateReader};
 use util::Xorshift;
+use roms::{Rom, RomSearch, SystemRom, CharacterRom, identify, crc32, load_file};
+use std::vec::bytes::copy_memory;
+use std::from_str::FromStr;
 
 pub static GR_TXMODE:  u8 = 1;
 pub static GR_MIXMODE: u8 = 2;
@@ -11,10 +16,83 @@ pub static HW_LO:	   u16 = 0xC000;
 pub static ROM_LO: 	   u16 = 0xD000;
 pub static ROM_LEN:	   u16 = 0x3000;
 
+// 65 cycles per scanline, 262 scanlines per (60Hz) frame
+pub static CYCLES_PER_FRAME: Cycles = 17030;
+
+// the members of the family that can be emulated
+#[deriving(Eq, Clone)]
+pub enum Model
+{
+   ModelII,            // the original, with Integer BASIC in ROM
+   ModelIIPlus,
+   ModelIIe,
+   ModelIIeEnhanced,   // the IIe with a 65C02 and the updated ROM
+}
+
+impl Model
+{
+   // identifies the machine in save files and movies
+   pub fn name(&self) -> &'static str
+   {
+      match *self {
+         ModelII => "Apple ][",
+         ModelIIPlus => "Apple ][+",
+         ModelIIe => "Apple //e",
+         ModelIIeEnhanced => "Enhanced Apple //e",
+      }
+   }
+
+   pub fn cpu_variant(&self) -> CpuVariant
+   {
+      match *self {
+         ModelIIeEnhanced => Cmos65C02,
+         _ => Nmos6502
+      }
+   }
+
+   // the IIe has aux memory, its extra soft switches and internal ROM at $C100-$CFFF
+   pub fn is_iie(&self) -> bool { *self == ModelIIe || *self == ModelIIeEnhanced }
+
+   // 12K from $D000, or for the IIe, 16K from $C000
+   pub fn rom_size(&self) -> uint { if self.is_iie() { 0x4000 } else { ROM_LEN as uint } }
+
+   // before the IIe, the keyboard only had capitals
+   pub fn has_lowercase(&self) -> bool { self.is_iie() }
+}
+
+// "ii", "ii+", "iie" or "enhanced-iie", as on the command line
+impl FromStr for Model
+{
+   fn from_str(s: &str) -> Option<Model>
+   {
+      match s {
+         "ii" => Some(ModelII),
+         "ii+" => Some(ModelIIPlus),
+         "iie" => Some(ModelIIe),
+         "enhanced-iie" => Some(ModelIIeEnhanced),
+         _ => None
+      }
+   }
+}
+
 pub trait Peripheral
 {
     fn doIO(&mut self, addr: u16, val: u8) -> u8;
     fn doHighIO(&mut self, addr: u16, val: u8) -> u8;
+    // a read of $CnXX for a debugger, which mustn't change the card's state
+    fn peekHighIO(&mut self, addr: u16) -> u8 { self.doHighIO(addr, 0) }
+    // interrupt lines; a card holds IRQ until software acknowledges it
+    fn irq(&mut self) -> bool { false }
+    fn nmi(&mut self) -> bool { false }
+    // one line describing the card's state, for debuggers
+    fn status(&self) -> ~str { ~"" }
+    // the ROMs on the card, one line each, for listing the machine's ROM set
+    fn rom_names(&self) -> ~[~str] { ~[] }
+    // snapshots, for rewinding; a card with no state of its own saves nothing. check_state
+    // is given what save_state wrote, and says whether load_state can load it
+    fn save_state(&self, _: &mut Writer) {}
+    fn check_state(&self, _: &[u8]) -> Result<(), ~str> { Ok(()) }
+    fn load_state(&mut self, _: &mut Reader) {}
 }
 
 struct LangCardState
@@ -23,6 +101,8 @@ struct LangCardState
    auxRAMselected: bool,
    auxRAMbank: u8,
    writeinhibit: bool,
+   // set by a read from an odd switch; a second one in a row write-enables the RAM
+   prewrite: bool,
 
    // value to add when reading & writing each of these banks
    // bank 1 is D000-FFFF, bank 2 is D000-DFFF
@@ -39,6 +119,7 @@ impl LangCardState
          auxRAMselected: auxRAMselected,
          auxRAMbank: auxRAMbank,
          writeinhibit: writeinhibit,
+         prewrite: false,
          // reset language card constants
          // 0x3000  = map 0xd000-0xffff -> 0x10000-0x12fff
          // -0x1000 = map 0xd000-0xdfff -> 0xc000-0xcfff
@@ -50,8 +131,45 @@ impl LangCardState
    }
 }
 
+// The IIe's extra soft switches, the 64K of auxiliary RAM on its extended 80-column card, and its
+// internal ROM at $C100-$CFFF
+struct IIeState
+{
+   store80: bool,    // PAGE2 switches the display pages between main and aux memory
+   ramrd: bool,      // $0200-$BFFF reads from aux memory
+   ramwrt: bool,     // $0200-$BFFF writes to aux memory
+   intcxrom: bool,   // $C100-$CFFF is the internal ROM
+   altzp: bool,      // zero page, stack and language card are in aux memory
+   slotc3rom: bool,  // $C300-$C3FF is slot 3's ROM, not the internal 80-column firmware
+   col80: bool,      // 80-column display
+   altcharset: bool,
+   // $C800-$CFFF is the internal ROM, from accessing $C3xx until $CFFF is
+   intc8rom: bool,
+   
+   // laid out like AppleII::mem, so the language card offsets work for either; the ROM area
+   // isn't used
+   auxmem: ~[u8],
+   // the $C000-$CFFF part of the IIe's ROM; $C000-$C0FF isn't used
+   cxrom: ~[u8],
+}
+
+impl IIeState
+{
+   // everything off, and the ROM to be loaded
+   fn new() -> IIeState
+   {
+      IIeState {
+         store80: false, ramrd: false, ramwrt: false, intcxrom: false, altzp: false,
+         slotc3rom: false, col80: false, altcharset: false, intc8rom: false,
+         auxmem: ~[0, ..0x13000],
+         cxrom: ~[0, ..0x1000],
+      }
+   }
+}
+
 pub struct AppleII
 {
+   model: Model,
    mem: [u8, ..0x13000],
    
    slots: [Option<~Peripheral>, ..8],
@@ -61,61 +179,90 @@ pub struct AppleII
    grswitch: u8,
    soundstate: bool,
    aux: LangCardState,
-   nreads: u16 // counts # of reads for noise() fn
+   iie: Option<IIeState>,
+   rom: ~str,  // describes the system ROM
+   chargen: Option<Rom>,  // the character ROM, if there's a dump of it; nothing draws from it yet
+
+   rng: Xorshift // for noise(); seeded the same every run, so runs can be replayed
 }
 
 impl Mem for AppleII
 {
     fn loadb(&mut self, addr: u16) -> u8
     {
-       self.nreads += 1;
        let val =
       // see if it's from main memory (0x0000-0xbfff)
       if (addr < HW_LO) {
-         self.mem[addr] & 0xff
+         let alt = self.auxSelected(addr, false);
+         self.ram(alt)[addr] & 0xff
       // see if it came from the ROM/LC area (0xd000-0xffff)
       } else if (addr >= ROM_LO) {
-         if (addr >= 0xe000) {
-            self.mem[addr as int + self.aux.bank1rdoffset] & 0xff
-         } else {
-            self.mem[addr as int + self.aux.bank2rdoffset] & 0xff
-         }
+         let offset = if (addr >= 0xe000) { self.aux.bank1rdoffset }
+                      else { self.aux.bank2rdoffset };
+         // the ROM is only in main memory
+         let alt = offset != 0 && self.auxSelected(addr, false);
+         self.ram(alt)[addr as int + offset] & 0xff
       }
       // it must be an I/O location (0xc000-0xcfff)
       else if (addr < HW_LO + 0x100) {
          let noise = self.noise(); // when reading, pass noise as value (we might get it back)
-         self.doIO(addr, noise)
+         self.doIO(addr, noise, false)
+      } else if (self.internalRomSelected(addr)) {
+         self.iie.get_ref().cxrom[addr - HW_LO]
       } else {
          match self.slots[(addr >> 8) & 7] {
             None    => self.noise(),
             Some(ref mut p) => p.doHighIO(addr, 0) // TODO: maybe have optional value, or new method
          }
       };
-      debug!("Read {:x} = {:x}", addr, val);
       return val;
     }
     
+    // memory and ROM read as they do for the CPU, but the soft switches, the IIe's $C800 ROM
+    // selection and the floating bus are left alone
+    fn peekb(&mut self, addr: u16) -> u8
+    {
+      if (addr < HW_LO || addr >= ROM_LO) {
+         self.loadb(addr)
+      } else if (addr < HW_LO + 0x10) {
+         self.kbdlatch
+      } else if (addr < HW_LO + 0x100) {
+         0
+      } else if (self.internalRomShown(addr)) {
+         self.iie.get_ref().cxrom[addr - HW_LO]
+      } else {
+         match self.slots[(addr >> 8) & 7] {
+            None    => 0,
+            Some(ref mut p) => p.peekHighIO(addr)
+         }
+      }
+    }
+    
     fn storeb(&mut self, addr: u16, val: u8)
     {
-       debug!("Write {:x} = {:x}", addr, val);
       // see if it's from main memory (0x0000-0xbfff)
       if (addr < HW_LO)
       {
-         self.mem[addr] = val;
+         let alt = self.auxSelected(addr, true);
+         self.ram(alt)[addr] = val;
          //dirty[addr >> 7] = true;
       }
       // see if it came from the ROM/LC area (0xd000-0xffff)
-      else if (addr >= ROM_LO && /* auxRAMselected && */ !self.aux.writeinhibit)
+      else if (addr >= ROM_LO)
       {
-         if (addr >= 0xe000) {
-            self.mem[addr as int + self.aux.bank1wroffset] = val;
-         } else {
-            self.mem[addr as int + self.aux.bank2wroffset] = val;
+         // with the RAM write protected, this is a write to ROM, which does nothing
+         if (!self.aux.writeinhibit) {
+            let offset = if (addr >= 0xe000) { self.aux.bank1wroffset }
+                         else { self.aux.bank2wroffset };
+            let alt = self.auxSelected(addr, true);
+            self.ram(alt)[addr as int + offset] = val;
          }
       }
       // it must be an I/O location (0xc000-0xcfff)
       else if (addr < HW_LO + 0x100) {
-         self.doIO(addr, val);
+         self.doIO(addr, val, true);
+      } else if (self.internalRomSelected(addr)) {
+         // ROM
       } else {
          match self.slots[(addr >> 8) & 7] {
             None    => (), // no-op
@@ -123,11 +270,143 @@ impl Mem for AppleII
          }
       }
     }
+
+    // the lines are wired-OR across all slots
+    fn irq_line(&mut self) -> bool
+    {
+      self.slots.mut_iter().any(|s| match *s { Some(ref mut p) => p.irq(), None => false })
+    }
+
+    fn nmi_line(&mut self) -> bool
+    {
+      self.slots.mut_iter().any(|s| match *s { Some(ref mut p) => p.nmi(), None => false })
+    }
+}
+
+impl Snapshot for AppleII
+{
+    // RAM and ROM, the soft switches, the language card, the IIe's switches and aux memory,
+    // then a chunk for each card
+    fn save_state(&self, w: &mut StateWriter)
+    {
+      w.chunk("MEM ", 1, |w| w.write(self.mem.as_slice()));
+      w.chunk("IO  ", 1, |w| {
+         w.write([self.kbdlatch, self.grswitch, self.soundstate as u8]);
+         for n in [self.rng.x, self.rng.y, self.rng.z, self.rng.w].iter() { w.write_le_u32(*n); }
+      });
+      w.chunk("LC  ", 2, |w| {
+         w.write([self.aux.auxRAMselected as u8, self.aux.auxRAMbank, self.aux.writeinhibit as u8,
+                  self.aux.prewrite as u8]);
+      });
+      match self.iie {
+         Some(ref e) => w.chunk("IIE ", 1, |w| {
+            w.write([e.store80 as u8, e.ramrd as u8, e.ramwrt as u8, e.intcxrom as u8,
+                     e.altzp as u8, e.slotc3rom as u8, e.col80 as u8, e.altcharset as u8,
+                     e.intc8rom as u8]);
+            w.write(e.auxmem.as_slice());
+         }),
+         None => ()
+      }
+      for slot in range(0u, 8)
+      {
+         let tag = format!("SLT{}", slot);
+         match self.slots[slot] {
+            Some(ref p) => w.chunk(tag, 1, |w| p.save_state(w)),
+            None => ()
+         }
+      }
+    }
+
+    fn check_state(&self, state: &StateReader) -> Result<(), ~str>
+    {
+      // the IO chunk is the switches and the noise generator's four words
+      let required = [("MEM ", self.mem.len()), ("IO  ", 3 + 16), ("LC  ", 3)];
+      for &(tag, len) in required.iter() {
+         match state.require(tag, len) {
+            Ok(()) => (),
+            err => return err
+         }
+      }
+      match (&self.iie, state.contents("IIE ")) {
+         (&Some(ref e), Some(_)) => match state.require("IIE ", 9 + e.auxmem.len()) {
+            Ok(()) => (),
+            err => return err
+         },
+         (&None, None) => (),
+         _ => return Err(~"the snapshot's memory doesn't match this machine's")
+      }
+      for slot in range(0u, 8)
+      {
+         let tag = format!("SLT{}", slot);
+         match (&self.slots[slot], state.contents(tag)) {
+            (&Some(ref p), Some(contents)) => match p.check_state(contents) {
+               Ok(()) => (),
+               err => return err
+            },
+            (&None, None) => (),
+            _ => return Err(~"the snapshot's cards don't match this machine's")
+         }
+      }
+      Ok(())
+    }
+
+    // only called once check_state has passed, so that a bad snapshot changes nothing
+    fn load_state(&mut self, state: &StateReader)
+    {
+      let mem = state.expect("MEM ").read_bytes(self.mem.len());
+      copy_memory(self.mem.mut_slice_from(0), mem);
+      let mut r = state.expect("IO  ");
+      let b = r.read_bytes(3);
+      self.kbdlatch = b[0];
+      self.grswitch = b[1];
+      self.soundstate = b[2] != 0;
+      let (x, y) = (r.read_le_u32(), r.read_le_u32());
+      let (z, w) = (r.read_le_u32(), r.read_le_u32());
+      self.rng = Xorshift { x: x, y: y, z: z, w: w };
+      let mut r = state.expect("LC  ");
+      let b = r.read_bytes(3);
+      self.aux = LangCardState::new(b[0] != 0, b[1], b[2] != 0);
+      // added in version 2
+      self.aux.prewrite = r.read_byte() == Some(1);
+      match (&mut self.iie, state.chunk("IIE ")) {
+         (&Some(ref mut e), Some((_, ref mut r))) => {
+            let b = r.read_bytes(9);
+            e.store80 = b[0] != 0;
+            e.ramrd = b[1] != 0;
+            e.ramwrt = b[2] != 0;
+            e.intcxrom = b[3] != 0;
+            e.altzp = b[4] != 0;
+            e.slotc3rom = b[5] != 0;
+            e.col80 = b[6] != 0;
+            e.altcharset = b[7] != 0;
+            e.intc8rom = b[8] != 0;
+            let auxmem = r.read_bytes(e.auxmem.len());
+            copy_memory(e.auxmem.mut_slice_from(0), auxmem);
+         }
+         (&None, None) => (),
+         _ => fail!("the snapshot's memory doesn't match this machine's")
+      }
+      for slot in range(0u, 8)
+      {
+         let tag = format!("SLT{}", slot);
+         match (&mut self.slots[slot], state.chunk(tag)) {
+            (&Some(ref mut p), Some((_, ref mut r))) => p.load_state(r),
+            (&None, None) => (),
+            _ => fail!("the snapshot's cards don't match this machine's")
+         }
+      }
+    }
 }
 
 impl AppleII
 {
-    pub fn new() -> AppleII { AppleII { 
+    // an Apple II+
+    pub fn new() -> AppleII { AppleII::with_model(ModelIIPlus) }
+    
+    pub fn with_model(model: Model) -> AppleII
+    {
+      AppleII {
+       model: model,
        mem:   [ 0, ..0x13000 ],
        // TODO: slots: [ None, ..8 ],
        // https://gist.github.com/carl-eastlund/6264938
@@ -137,9 +416,18 @@ impl AppleII
        kbdlatch: 0,
        grswitch: 0,
        soundstate: false,
-       nreads: 0
-    } }
+       iie: if (model.is_iie()) { Some(IIeState::new()) } else { None },
+       rom: ~"none",
+       chargen: None,
+       rng: Xorshift::new()
+      }
+    }
     
+    pub fn model(&self) -> Model { self.model }
+    
+    // identifies the machine in save files
+    pub fn model_name(&self) -> &'static str { self.model.name() }
+
     pub fn set_slot(&mut self, slot: uint, mut p: ~Peripheral)
     {
       //p.doIO(0,0);
@@ -147,7 +435,134 @@ impl AppleII
       //self.slots[slot].get_mut_ref().doIO(0,0);
     }
     
-    fn noise(&mut self) -> u8 { self.mem[self.nreads & 0xffff] }
+    pub fn has_aux_memory(&self) -> bool { self.iie.is_some() }
+    
+    // main memory as the display sees it, $0000-$BFFF, for drawing it
+    pub fn mem<'a>(&'a self) -> &'a [u8] { self.mem.slice_to(HW_LO as uint) }
+    
+    // the aux memory, for drawing the 80-column display
+    pub fn aux_mem<'a>(&'a self) -> Option<&'a [u8]>
+    {
+      self.iie.as_ref().map(|e| e.auxmem.as_slice())
+    }
+    
+    pub fn col80(&self) -> bool { self.iie.as_ref().map_or(false, |e| e.col80) }
+    
+    // whether the display shows page 2; with 80STORE on, PAGE2 switches memory banks instead
+    pub fn display_page2(&self) -> bool
+    {
+      (self.grswitch & GR_PAGE1) != 0 && !self.iie.as_ref().map_or(false, |e| e.store80)
+    }
+    
+    fn noise(&mut self) -> u8 { self.mem[self.rng.next() & 0xffff] }
+    
+    // whether an access to RAM or the language card goes to aux memory
+    fn auxSelected(&self, addr: u16, write: bool) -> bool
+    {
+      match self.iie {
+         None => false,
+         Some(ref e) => {
+            let page2 = (self.grswitch & GR_PAGE1) != 0;
+            let hires = (self.grswitch & GR_HIRES) != 0;
+            if (addr < 0x200 || addr >= ROM_LO) {
+               e.altzp
+            } else if (e.store80 && addr >= 0x400 && addr < 0x800) {
+               page2
+            } else if (e.store80 && hires && addr >= 0x2000 && addr < 0x4000) {
+               page2
+            } else if (write) {
+               e.ramwrt
+            } else {
+               e.ramrd
+            }
+         }
+      }
+    }
+    
+    fn ram<'a>(&'a mut self, alt: bool) -> &'a mut [u8]
+    {
+      if (alt) {
+         self.iie.get_mut_ref().auxmem.mut_slice_from(0)
+      } else {
+         self.mem.mut_slice_from(0)
+      }
+    }
+    
+    // whether $C100-$CFFF is the IIe's internal ROM rather than a card's
+    fn internalRomShown(&self, addr: u16) -> bool
+    {
+      match self.iie {
+         None => false,
+         Some(ref e) => {
+            let c3 = addr >= 0xc300 && addr < 0xc400;
+            let c8 = addr >= 0xc800 && addr < ROM_LO;
+            e.intcxrom || (c3 && !e.slotc3rom) || (c8 && e.intc8rom)
+         }
+      }
+    }
+    
+    // like internalRomShown, for an access, which may switch $C800-$CFFF between them
+    fn internalRomSelected(&mut self, addr: u16) -> bool
+    {
+      let internal = self.internalRomShown(addr);
+      match self.iie {
+         None => (),
+         Some(ref mut e) => {
+            if (addr >= 0xc300 && addr < 0xc400 && !e.slotc3rom) { e.intc8rom = true; }
+            if (addr == 0xcfff) { e.intc8rom = false; }
+         }
+      }
+      internal
+    }
+    
+    // writes to $C000-$C00F set the IIe's switches, even addresses off and odd on
+    fn setIIeSwitch(&mut self, addr: u16)
+    {
+      match self.iie {
+         None => (),
+         Some(ref mut e) => {
+            let on = (addr & 1) != 0;
+            match (addr & 0x0f) >> 1 {
+               0 => e.store80 = on,
+               1 => e.ramrd = on,
+               2 => e.ramwrt = on,
+               3 => e.intcxrom = on,
+               4 => e.altzp = on,
+               5 => e.slotc3rom = on,
+               6 => e.col80 = on,
+               _ => e.altcharset = on
+            }
+         }
+      }
+    }
+    
+    // reads of $C011-$C01F give a switch in bit 7, and the keyboard latch in the rest
+    fn readIIeSwitch(&mut self, addr: u16) -> u8
+    {
+      let g = self.grswitch;
+      let on = match self.iie {
+         None => fail!(),
+         Some(ref e) => match addr & 0x0f {
+            1 => self.aux.auxRAMbank == 2,
+            2 => self.aux.auxRAMselected,
+            3 => e.ramrd,
+            4 => e.ramwrt,
+            5 => e.intcxrom,
+            6 => e.altzp,
+            7 => e.slotc3rom,
+            8 => e.store80,
+            // vertical blanking isn't modeled, so it's random, which lets waits for it finish
+            9 => (self.rng.next() & 1) != 0,
+            10 => (g & GR_TXMODE) != 0,
+            11 => (g & GR_MIXMODE) != 0,
+            12 => (g & GR_PAGE1) != 0,
+            13 => (g & GR_HIRES) != 0,
+            14 => e.altcharset,
+            _ => e.col80
+         }
+      };
+      (if on { 0x80 } else { 0 }) | (self.kbdlatch & 0x7f)
+    }
     
     fn setGrSwitch(&mut self, addr: u16)
     {
@@ -175,18 +590,20 @@ impl AppleII
          }
     }
     
-    fn doIO(&mut self, addr: u16, val: u8) -> u8
+    fn doIO(&mut self, addr: u16, val: u8, write: bool) -> u8
     {
        debug!("doIO({:x}, {:x})", addr, val);
        let slot = (addr >> 4) & 0x0f;
        match slot {
+          0	if (write) => { self.setIIeSwitch(addr); self.noise() }
           0	=> self.kbdlatch,			// keyboard
+          1	if (!write && addr != 0xc010 && self.iie.is_some()) => self.readIIeSwitch(addr),
           1	=> { self.clearStrobe(); self.noise() }		// reset kbd strobe
           3	=> { self.soundstate = !self.soundstate; self.noise() }		// speaker
           5	=> { if ((addr & 0x0f) < 8) { self.setGrSwitch(addr); } else { self.setAnnunciator(addr); } self.noise() }
           6	=> self.fakeJoystick(addr),
           7	if (addr == 0xc070) => self.noise() | 0x80, // joystick reset
-          8	=> { self.doLanguageCardIO(addr); self.noise() }
+          8	=> { self.doLanguageCardIO(addr, write); self.noise() }
           9..15 => match self.slots[slot-8] {
              None => self.noise(),
              Some(ref mut p) => p.doIO(addr, val)
@@ -204,46 +621,150 @@ impl AppleII
    pub fn keyPressed(&mut self, keycode: u8)
    {
       let mut key = (keycode | 0x80) & 0xff;
-      // since we're an Apple II+, we don't do lowercase
-      if (key >= 0xe1 && key <= 0xfa) { key -= 0x20; }
+      // before the IIe, there's no lowercase
+      if (!self.model.has_lowercase() && key >= 0xe1 && key <= 0xfa) { key -= 0x20; }
       self.kbdlatch = key;
       debug!("Key pressed: {}", key);
    }
 
-   fn doLanguageCardIO(&mut self, addr:u16)
+   // $C080-$C08F: bit 3 selects bank 1 rather than bank 2 (bit 2 is ignored), and bits 0-1 are
+   //   0: read RAM, write protected
+   //   1: read ROM, write enable RAM
+   //   2: read ROM, write protected
+   //   3: read RAM, write enable RAM
+   // Write enabling takes two reads in a row from odd switches: the first sets the pre-write
+   // latch, and the second, with it set, enables writes. Writing to an odd switch resets the
+   // latch without protecting the RAM, and any access to an even switch resets both.
+   fn doLanguageCardIO(&mut self, addr: u16, write: bool)
    {
-      self.aux = match addr & 0xf
+      let bank = if (addr & 8) != 0 { 1 } else { 2 };
+      let readram = (addr & 3) == 0 || (addr & 3) == 3;
+      let mut writeinhibit = self.aux.writeinhibit;
+      let mut prewrite = self.aux.prewrite;
+      if ((addr & 1) == 0) {
+         writeinhibit = true;
+         prewrite = false;
+      } else if (write) {
+         prewrite = false;
+      } else {
+         if (prewrite) { writeinhibit = false; }
+         prewrite = true;
+      }
+      self.aux = LangCardState::new(readram, bank, writeinhibit);
+      self.aux.prewrite = prewrite;
+      debug!("language card switch {:x}", addr);
+   }
+   
+   // describes the soft switch state, one line per subsystem
+   pub fn status(&self) -> ~[~str]
+   {
+      let g = self.grswitch;
+      let mode = if (g & GR_TXMODE) != 0 { "text" } else if (g & GR_HIRES) != 0 { "hires" } else { "lores" };
+      let mixed = (g & GR_TXMODE) == 0 && (g & GR_MIXMODE) != 0;
+      let mut lines = ~[
+         format!("video: {}{}, page {}", mode, if mixed { " mixed" } else { "" },
+                 if (g & GR_PAGE1) != 0 { 2 } else { 1 }),
+         format!("language card: read {}, write {}, bank {}{}",
+                 if self.aux.auxRAMselected { "RAM" } else { "ROM" },
+                 if self.aux.writeinhibit { "off" } else { "RAM" },
+                 self.aux.auxRAMbank, if self.aux.prewrite { ", pre-write" } else { "" }),
+      ];
+      match self.iie
       {
-       // Select aux RAM bank 2, write protected.
-       0|4 => LangCardState::new(true, 2, true),
-       // Select ROM, write enable aux RAM bank 2.
-       1|5 => LangCardState::new(false, 2, false),
-       // Select ROM, write protect aux RAM (either bank).
-       2|6|10|14 => LangCardState::new(false, self.aux.auxRAMbank, true),
-       // Select aux RAM bank 2, write enabled.
-       3|7 => LangCardState::new(true, 2, false),
-       // Select aux RAM bank 1, write protected.
-       8|12 => LangCardState::new(true, 1, false), 
-       // Select ROM, write enable aux RAM bank 1.
-       9|13 => LangCardState::new(false, 1, false), 
-       // Select aux RAM bank 1, write enabled.
-       11|15 => LangCardState::new(true, 1, false),
-       // TODO: shouldn't need this
-       _ => fail!()
+         Some(ref e) => {
+            let switches = [(e.store80, "80STORE"), (e.ramrd, "RAMRD"), (e.ramwrt, "RAMWRT"),
+                            (e.intcxrom, "INTCXROM"), (e.altzp, "ALTZP"),
+                            (e.slotc3rom, "SLOTC3ROM"), (e.col80, "80COL"),
+                            (e.altcharset, "ALTCHARSET")];
+            let on: ~[&str] = switches.iter().filter(|&&(on, _)| on)
+                                      .map(|&(_, name)| name).collect();
+            lines.push(format!("IIe: {}", if on.len() > 0 { on.connect(" ") } else { ~"all off" }));
+         }
+         None => ()
       }
+      for slot in range(1u, 8)
+      {
+         match self.slots[slot] {
+            Some(ref p) => lines.push(format!("slot {}: {}", slot, p.status())),
+            None => ()
+         }
+      }
+      // the system ROM last, after the rest of the set
+      let names = self.rom_names();
+      for name in names.slice_from(1).iter() { lines.push(format!("ROM: {}", *name)); }
+      lines.push(format!("{}: {}", self.model.name(), self.rom));
+      lines
    }
+
+   // puts the model's ROM in place; the last 12K is at $D000, and a IIe's first 4K is its
+   // internal ROM at $C000-$CFFF
+   pub fn load_rom(&mut self, rom: &[u8])
+   {
+      assert!(rom.len() == self.model.rom_size());
+      let top = rom.slice_from(rom.len() - ROM_LEN as uint);
+      copy_memory(self.mem.mut_slice(0xd000, 0xd000+0x3000), top);
+      match self.iie {
+         Some(ref mut e) => copy_memory(e.cxrom.mut_slice_from(0), rom.slice(0, 0x1000)),
+         None => ()
+      }
+      self.rom = match identify(SystemRom, rom) {
+         Some(known) => known.name.to_owned(),
+         None => format!("unrecognized system ROM, CRC-32 {:08x}", crc32(rom))
+      };
+   }
+
+   // finds the model's ROM and loads it, and its character ROM if there's one to be found
+   pub fn read_roms(&mut self, search: &RomSearch) -> Result<(), ~str>
+   {
+      let rom = match search.load(SystemRom, self.model) {
+         Ok(rom) => rom,
+         Err(e) => return Err(e)
+      };
+      self.load_rom(rom.data);
+      self.rom = rom.describe();
+      info!("loaded {}", self.rom);
+      for path in search.find(CharacterRom, self.model).move_iter() {
+         match load_file(CharacterRom, self.model, path) {
+            Ok(rom) => {
+               info!("loaded {}", rom.describe());
+               self.chargen = Some(rom);
+            }
+            Err(e) => return Err(e)
+         }
+      }
+      Ok(())
+   }
+   
+   // which system ROM is in use
+   pub fn rom_name<'a>(&'a self) -> &'a str { self.rom.as_slice() }
    
-   pub fn read_roms(&mut self)
+   pub fn character_rom<'a>(&'a self) -> Option<&'a [u8]>
    {
-      use std::io::File;
-      use std::vec::bytes::copy_memory;
-      let ap2rom = File::open(&Path::new("apple2.rom")).read_bytes(0x3000);
-      match ap2rom {
-       Ok(rom) => {
-        copy_memory(self.mem.mut_slice(0xd000, 0xd000+0x3000), rom);
-        info!("loaded apple2.rom");
-       }
-       Err(e) => fail!(e)
+      self.chargen.as_ref().map(|rom| rom.data.as_slice())
+   }
+   
+   // every ROM in use: the system ROM, the character ROM and the cards'
+   pub fn rom_names(&self) -> ~[~str]
+   {
+      let mut names = ~[self.rom.clone()];
+      for rom in self.chargen.iter() { names.push(rom.describe()); }
+      for slot in self.slots.iter() {
+         match *slot {
+            Some(ref p) => names.push_all_move(p.rom_names()),
+            None => ()
+         }
       }
+      names
+   }
+}
+
+impl Cpu<AppleII>
+{
+   // runs to the end of the current video frame; frames start every CYCLES_PER_FRAME cycles
+   pub fn run_frame(&mut self) -> StopReason
+   {
+      let end = (self.cycles() / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+      let cycles = end - self.cycles();
+      self.run_cycles(cycles)
    }
 }
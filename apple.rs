@@ -7,6 +7,8 @@
 #[feature(link_args, macro_rules)];
 //#[no_main];
 
+extern mod extra;
+
 //extern mod native;
 //extern mod sdl;
 
@@ -16,9 +18,18 @@ pub mod util;
 
 #[macro_escape]
 pub mod cpu;
+pub mod disasm;
+pub mod trace;
+pub mod debugger;
+pub mod state;
+pub mod rewind;
+pub mod movie;
+pub mod monitor;
+pub mod gdbstub;
 pub mod mem;
 pub mod a2;
 pub mod diskii;
+pub mod roms;
 
 mod tests;
 
@@ -4,7 +4,11 @@
 // Author: Patrick Walton
 //
 
-use mem::{Mem, MemUtil};
+use mem::{Mem, MemUtil, BusHook, BusAccess};
+use std::fmt;
+use std::util::replace;
+use trace::Tracer;
+use state::{Snapshot, StateWriter, StateReader};
 
 //
 // Constants
@@ -15,6 +19,7 @@ static ZERO_FLAG:     u8 = 1 << 1;
 static IRQ_FLAG:      u8 = 1 << 2;
 static DECIMAL_FLAG:  u8 = 1 << 3;
 static BREAK_FLAG:    u8 = 1 << 4;
+static UNUSED_FLAG:   u8 = 1 << 5;
 static OVERFLOW_FLAG: u8 = 1 << 6;
 static NEGATIVE_FLAG: u8 = 1 << 7;
 
@@ -22,6 +27,13 @@ static NMI_VECTOR:   u16 = 0xfffa;
 static RESET_VECTOR: u16 = 0xfffc; 
 static BRK_VECTOR:   u16 = 0xfffe;
 
+static INTERRUPT_CYCLES: Cycles = 7;
+
+// The instructions whose change to I the next IRQ poll doesn't see yet
+static PLP_OPCODE: u8 = 0x28;
+static CLI_OPCODE: u8 = 0x58;
+static SEI_OPCODE: u8 = 0x78;
+
 /// The number of cycles that each machine operation takes. Indexed by opcode number.
 ///
 /// FIXME: This is copied from FCEU.
@@ -41,15 +53,81 @@ static CYCLE_TABLE: [u8, ..256] = [
     /*0xB0*/ 2,5,2,5,4,4,4,4,2,4,2,4,4,4,4,4,
     /*0xC0*/ 2,6,2,8,3,3,5,5,2,2,2,2,4,4,6,6,
     /*0xD0*/ 2,5,2,8,4,4,6,6,2,4,2,7,4,4,7,7,
-    /*0xE0*/ 2,6,3,8,3,3,5,5,2,2,2,2,4,4,6,6,
+    /*0xE0*/ 2,6,2,8,3,3,5,5,2,2,2,2,4,4,6,6,
     /*0xF0*/ 2,5,2,8,4,4,6,6,2,4,2,7,4,4,7,7,
 ];
 
+/// Cycle counts for the 65C02. The opcodes the NMOS part leaves undefined are all NOPs here, most
+/// of them single-cycle.
+static CYCLE_TABLE_65C02: [u8, ..256] = [
+    /*0x00*/ 7,6,2,1,5,3,5,1,3,2,2,1,6,4,6,1,
+    /*0x10*/ 2,5,5,1,5,4,6,1,2,4,2,1,6,4,6,1,
+    /*0x20*/ 6,6,2,1,3,3,5,1,4,2,2,1,4,4,6,1,
+    /*0x30*/ 2,5,5,1,4,4,6,1,2,4,2,1,4,4,6,1,
+    /*0x40*/ 6,6,2,1,3,3,5,1,3,2,2,1,3,4,6,1,
+    /*0x50*/ 2,5,5,1,4,4,6,1,2,4,3,1,8,4,6,1,
+    /*0x60*/ 6,6,2,1,3,3,5,1,4,2,2,1,6,4,6,1,
+    /*0x70*/ 2,5,5,1,4,4,6,1,2,4,4,1,6,4,6,1,
+    /*0x80*/ 2,6,2,1,3,3,3,1,2,2,2,1,4,4,4,1,
+    /*0x90*/ 2,6,5,1,4,4,4,1,2,5,2,1,4,5,5,1,
+    /*0xA0*/ 2,6,2,1,3,3,3,1,2,2,2,1,4,4,4,1,
+    /*0xB0*/ 2,5,5,1,4,4,4,1,2,4,2,1,4,4,4,1,
+    /*0xC0*/ 2,6,2,1,3,3,5,1,2,2,2,1,4,4,6,1,
+    /*0xD0*/ 2,5,5,1,4,4,6,1,2,4,3,1,4,4,7,1,
+    /*0xE0*/ 2,6,2,1,3,3,5,1,2,2,2,1,4,4,6,1,
+    /*0xF0*/ 2,5,5,1,4,4,6,1,2,4,4,1,4,4,7,1,
+];
+
+/// Which member of the 6502 family to emulate.
+#[deriving(Eq, Clone)]
+pub enum CpuVariant {
+    /// The original NMOS 6502 (Apple II, II+, unenhanced IIe).
+    Nmos6502,
+    /// The CMOS 65C02 without the Rockwell bit instructions (enhanced IIe, IIc).
+    Cmos65C02,
+}
+
+/// What to do on the NMOS undocumented opcodes whose results depend on the individual chip (ANE,
+/// LXA, SHA, SHX, SHY and TAS).
+#[deriving(Eq, Clone)]
+pub enum UnstableOpPolicy {
+    /// Execute them using the behavior most chips show.
+    EmulateUnstable,
+    /// Halt the CPU at the instruction, as `Trapped`.
+    TrapUnstable,
+    /// Log a warning and skip them as NOPs of the right length.
+    WarnUnstable,
+}
+
+/// Why the CPU has stopped executing instructions. Both leave the PC at the offending opcode and
+/// are cleared by `reset`.
+#[deriving(Eq, Clone)]
+pub enum Halt {
+    /// A JAM opcode locked up the processor.
+    Jammed(u8),
+    /// An unstable opcode was hit while `TrapUnstable` was in effect.
+    Trapped(u8),
+}
+
+/// Why one of the `run_*` methods returned.
+#[deriving(Eq, Clone)]
+pub enum StopReason {
+    /// The cycle budget ran out.
+    RanCycles,
+    /// The PC reached the address passed to `run_until_pc`.
+    ReachedPc,
+    /// The predicate passed to `run_until` returned true.
+    ConditionMet,
+    /// The CPU halted.
+    Halted(Halt),
+}
+
 //
 // Registers
 //
 
-struct Regs {
+#[deriving(Clone, Eq)]
+pub struct Regs {
     pub a: u8,
     pub x: u8,
     pub y: u8,
@@ -59,7 +137,15 @@ struct Regs {
 }
 
 impl Regs {
-    fn new() -> Regs { Regs { a: 0, x: 0, y: 0, s: 0xfd, flags: 0x24, pc: 0xc000 } }
+    /// The power-on state. A reset then leaves S at $FD.
+    pub fn new() -> Regs { Regs { a: 0, x: 0, y: 0, s: 0, flags: 0x24, pc: 0xc000 } }
+}
+
+impl fmt::Default for Regs {
+    fn fmt(regs: &Regs, f: &mut fmt::Formatter) {
+        write!(f.buf, "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}",
+               regs.a, regs.x, regs.y, regs.flags, regs.s, regs.pc)
+    }
 }
 
 //
@@ -69,6 +155,13 @@ impl Regs {
 trait AddressingMode<M> {
     fn load(&self, cpu: &mut Cpu<M>) -> u8;
     fn store(&self, cpu: &mut Cpu<M>, val: u8);
+
+    // Read-modify-write instructions always spend the cycle for the index fixup (it's in the
+    // cycle table), so they must not pay the page crossing penalty a plain load does.
+    fn load_rmw(&self, cpu: &mut Cpu<M>) -> u8 { self.load(cpu) }
+
+    // ...and they spend a cycle on the bus before writing the result (see `Cpu::rmw_dummy`).
+    fn store_rmw(&self, cpu: &mut Cpu<M>, _: u8, val: u8) { self.store(cpu, val) }
 }
 
 struct AccumulatorAddressingMode;
@@ -90,6 +183,47 @@ struct MemoryAddressingMode(u16);
 impl<M:Mem> AddressingMode<M> for MemoryAddressingMode {
     fn load(&self, cpu: &mut Cpu<M>) -> u8 { match *self { MemoryAddressingMode(addr) =>  cpu.loadb(addr) } }
     fn store(&self, cpu: &mut Cpu<M>, val: u8) { match *self { MemoryAddressingMode(addr) => cpu.storeb(addr, val) } }
+    fn store_rmw(&self, cpu: &mut Cpu<M>, old: u8, val: u8) {
+        match *self {
+            MemoryAddressingMode(addr) => {
+                cpu.rmw_dummy(addr, old);
+                cpu.storeb(addr, val)
+            }
+        }
+    }
+}
+
+// Indexed modes (abs,X, abs,Y and (zp),Y) remember the unindexed base address. The NMOS 6502
+// adds the index to the low byte first and reads from that address while it fixes up the high
+// byte; the 65C02 re-reads the last byte of the instruction instead. Loads only spend that extra
+// cycle when adding the index crosses a page boundary.
+struct IndexedAddressingMode { base: u16, addr: u16 }
+impl IndexedAddressingMode {
+    fn crossed(&self) -> bool { (self.base ^ self.addr) & 0xff00 != 0 }
+    fn unfixed(&self) -> u16 { (self.base & 0xff00) | (self.addr & 0x00ff) }
+    fn fixup_addr<M:Mem>(&self, cpu: &Cpu<M>) -> u16 {
+        if cpu.cmos() { cpu.regs.pc - 1 } else { self.unfixed() }
+    }
+}
+impl<M:Mem> AddressingMode<M> for IndexedAddressingMode {
+    fn load(&self, cpu: &mut Cpu<M>) -> u8 {
+        if self.crossed() {
+            cpu.penalty_read(self.fixup_addr(cpu));
+        }
+        cpu.loadb(self.addr)
+    }
+    fn store(&self, cpu: &mut Cpu<M>, val: u8) {
+        cpu.dummy_read(self.fixup_addr(cpu));
+        cpu.storeb(self.addr, val)
+    }
+    fn load_rmw(&self, cpu: &mut Cpu<M>) -> u8 {
+        cpu.dummy_read(self.fixup_addr(cpu));
+        cpu.loadb(self.addr)
+    }
+    fn store_rmw(&self, cpu: &mut Cpu<M>, old: u8, val: u8) {
+        cpu.rmw_dummy(self.addr, old);
+        cpu.storeb(self.addr, val)
+    }
 }
 
 //
@@ -297,7 +431,142 @@ macro_rules! decode_op {
             // No operation
             0xea => $this.nop(),
 
-            _ => warn!("unimplemented or illegal instruction")
+            // 65C02 additions
+            0x12 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.ora(v) }
+            0x32 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.and(v) }
+            0x52 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.eor(v) }
+            0x72 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.adc(v) }
+            0x92 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.sta(v) }
+            0xb2 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.lda(v) }
+            0xd2 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.cmp(v) }
+            0xf2 if $this.cmos() => { let v = $this.zero_page_indirect(); $this.sbc(v) }
+
+            0x89 if $this.cmos() => { let v = $this.immediate(); $this.bit_imm(v) }
+            0x34 if $this.cmos() => { let v = $this.zero_page_x(); $this.bit(v) }
+            0x3c if $this.cmos() => { let v = $this.absolute_x(); $this.bit(v) }
+
+            0x64 if $this.cmos() => { let v = $this.zero_page(); $this.stz(v) }
+            0x74 if $this.cmos() => { let v = $this.zero_page_x(); $this.stz(v) }
+            0x9c if $this.cmos() => { let v = $this.absolute(); $this.stz(v) }
+            0x9e if $this.cmos() => { let v = $this.absolute_x(); $this.stz(v) }
+
+            0x14 if $this.cmos() => { let v = $this.zero_page(); $this.trb(v) }
+            0x1c if $this.cmos() => { let v = $this.absolute(); $this.trb(v) }
+            0x04 if $this.cmos() => { let v = $this.zero_page(); $this.tsb(v) }
+            0x0c if $this.cmos() => { let v = $this.absolute(); $this.tsb(v) }
+
+            0x1a if $this.cmos() => { let v = $this.accumulator(); $this.inc(v) }
+            0x3a if $this.cmos() => { let v = $this.accumulator(); $this.dec(v) }
+
+            0x80 if $this.cmos() => $this.bra(),
+            0x7c if $this.cmos() => $this.jmpix(),
+
+            0xda if $this.cmos() => $this.phx(),
+            0x5a if $this.cmos() => $this.phy(),
+            0xfa if $this.cmos() => $this.plx(),
+            0x7a if $this.cmos() => $this.ply(),
+
+            // Everything else is a NOP on the 65C02, but some of them read operands.
+            0x02 | 0x22 | 0x42 | 0x62 | 0x82 | 0xc2 | 0xe2 if $this.cmos() => {
+                let v = $this.immediate(); $this.nop_read(v)
+            }
+            0x44 if $this.cmos() => { let v = $this.zero_page(); $this.nop_read(v) }
+            0x54 | 0xd4 | 0xf4 if $this.cmos() => { let v = $this.zero_page_x(); $this.nop_read(v) }
+            0x5c | 0xdc | 0xfc if $this.cmos() => { let v = $this.absolute(); $this.nop_read(v) }
+            _ if $this.cmos() => $this.nop(),
+
+            // NMOS undocumented instructions
+            0x03 => { let v = $this.indexed_indirect_x(); $this.slo(v) }
+            0x07 => { let v = $this.zero_page(); $this.slo(v) }
+            0x0f => { let v = $this.absolute(); $this.slo(v) }
+            0x13 => { let v = $this.indirect_indexed_y(); $this.slo(v) }
+            0x17 => { let v = $this.zero_page_x(); $this.slo(v) }
+            0x1b => { let v = $this.absolute_y(); $this.slo(v) }
+            0x1f => { let v = $this.absolute_x(); $this.slo(v) }
+
+            0x23 => { let v = $this.indexed_indirect_x(); $this.rla(v) }
+            0x27 => { let v = $this.zero_page(); $this.rla(v) }
+            0x2f => { let v = $this.absolute(); $this.rla(v) }
+            0x33 => { let v = $this.indirect_indexed_y(); $this.rla(v) }
+            0x37 => { let v = $this.zero_page_x(); $this.rla(v) }
+            0x3b => { let v = $this.absolute_y(); $this.rla(v) }
+            0x3f => { let v = $this.absolute_x(); $this.rla(v) }
+
+            0x43 => { let v = $this.indexed_indirect_x(); $this.sre(v) }
+            0x47 => { let v = $this.zero_page(); $this.sre(v) }
+            0x4f => { let v = $this.absolute(); $this.sre(v) }
+            0x53 => { let v = $this.indirect_indexed_y(); $this.sre(v) }
+            0x57 => { let v = $this.zero_page_x(); $this.sre(v) }
+            0x5b => { let v = $this.absolute_y(); $this.sre(v) }
+            0x5f => { let v = $this.absolute_x(); $this.sre(v) }
+
+            0x63 => { let v = $this.indexed_indirect_x(); $this.rra(v) }
+            0x67 => { let v = $this.zero_page(); $this.rra(v) }
+            0x6f => { let v = $this.absolute(); $this.rra(v) }
+            0x73 => { let v = $this.indirect_indexed_y(); $this.rra(v) }
+            0x77 => { let v = $this.zero_page_x(); $this.rra(v) }
+            0x7b => { let v = $this.absolute_y(); $this.rra(v) }
+            0x7f => { let v = $this.absolute_x(); $this.rra(v) }
+
+            0x83 => { let v = $this.indexed_indirect_x(); $this.sax(v) }
+            0x87 => { let v = $this.zero_page(); $this.sax(v) }
+            0x8f => { let v = $this.absolute(); $this.sax(v) }
+            0x97 => { let v = $this.zero_page_y(); $this.sax(v) }
+
+            0xa3 => { let v = $this.indexed_indirect_x(); $this.lax(v) }
+            0xa7 => { let v = $this.zero_page(); $this.lax(v) }
+            0xaf => { let v = $this.absolute(); $this.lax(v) }
+            0xb3 => { let v = $this.indirect_indexed_y(); $this.lax(v) }
+            0xb7 => { let v = $this.zero_page_y(); $this.lax(v) }
+            0xbf => { let v = $this.absolute_y(); $this.lax(v) }
+
+            0xc3 => { let v = $this.indexed_indirect_x(); $this.dcp(v) }
+            0xc7 => { let v = $this.zero_page(); $this.dcp(v) }
+            0xcf => { let v = $this.absolute(); $this.dcp(v) }
+            0xd3 => { let v = $this.indirect_indexed_y(); $this.dcp(v) }
+            0xd7 => { let v = $this.zero_page_x(); $this.dcp(v) }
+            0xdb => { let v = $this.absolute_y(); $this.dcp(v) }
+            0xdf => { let v = $this.absolute_x(); $this.dcp(v) }
+
+            0xe3 => { let v = $this.indexed_indirect_x(); $this.isc(v) }
+            0xe7 => { let v = $this.zero_page(); $this.isc(v) }
+            0xef => { let v = $this.absolute(); $this.isc(v) }
+            0xf3 => { let v = $this.indirect_indexed_y(); $this.isc(v) }
+            0xf7 => { let v = $this.zero_page_x(); $this.isc(v) }
+            0xfb => { let v = $this.absolute_y(); $this.isc(v) }
+            0xff => { let v = $this.absolute_x(); $this.isc(v) }
+
+            0x0b | 0x2b => { let v = $this.immediate(); $this.anc(v) }
+            0x4b => { let v = $this.immediate(); $this.alr(v) }
+            0x6b => { let v = $this.immediate(); $this.arr(v) }
+            0xcb => { let v = $this.immediate(); $this.sbx(v) }
+            0xeb => { let v = $this.immediate(); $this.sbc(v) }
+            0xbb => { let v = $this.absolute_y(); $this.las(v) }
+
+            // Unstable ones, subject to the UnstableOpPolicy
+            0x8b => { let v = $this.immediate(); $this.ane($op, v) }
+            0xab => { let v = $this.immediate(); $this.lxa($op, v) }
+            0x93 => { let v = $this.indirect_indexed_y(); $this.sha($op, v) }
+            0x9f => { let v = $this.absolute_y(); $this.sha($op, v) }
+            0x9e => { let v = $this.absolute_y(); $this.shx($op, v) }
+            0x9c => { let v = $this.absolute_x(); $this.shy($op, v) }
+            0x9b => { let v = $this.absolute_y(); $this.tas($op, v) }
+
+            // Undocumented NOPs, which still fetch (and read) their operands
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => $this.nop(),
+            0x80 | 0x82 | 0x89 | 0xc2 | 0xe2 => { let v = $this.immediate(); $this.nop_read(v) }
+            0x04 | 0x44 | 0x64 => { let v = $this.zero_page(); $this.nop_read(v) }
+            0x14 | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4 => {
+                let v = $this.zero_page_x(); $this.nop_read(v)
+            }
+            0x0c => { let v = $this.absolute(); $this.nop_read(v) }
+            0x1c | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc => {
+                let v = $this.absolute_x(); $this.nop_read(v)
+            }
+
+            // All that's left are the opcodes that lock up the processor: $02, $12, $22, $32, $42,
+            // $52, $62, $72, $92, $B2, $D2 and $F2.
+            _ => $this.jam($op)
         }
     }
 }
@@ -306,76 +575,193 @@ macro_rules! decode_op {
 // Main CPU implementation
 //
 
-type Cycles = u64;
+pub type Cycles = u64;
+
+/// A bus hook and the (inclusive) address range it was registered for.
+struct Hook {
+    lo: u16,
+    hi: u16,
+    hook: ~BusHook,
+}
 
 /// The main CPU structure definition.
 pub struct Cpu<M> {
     cy: Cycles,
     regs: Regs,
     mem: M,
+    hooks: ~[Hook],
+    variant: CpuVariant,
+    unstable_policy: UnstableOpPolicy,
+    halt: Option<Halt>,
+    nmi_prev: bool,     // the NMI line level at the last instruction boundary
+    irq_masked: Option<bool>,   // what the next IRQ poll sees as the I flag, if not the flag itself
+    cycle_accurate: bool,
+    prefetch: Option<u8>,   // the byte after the opcode, read on cycle 2
+    bus_log: Option<~[BusAccess]>,
+    tracer: Option<Tracer>,
+    penalty: Cycles,        // cycles the current instruction spends beyond its cycle table entry
 }
 
-// The CPU implements Mem so that it can handle writes to the DMA register.
-impl<M:Mem> Mem for Cpu<M> {
-    fn loadb(&mut self, addr: u16) -> u8 { self.mem.loadb(addr) }
-    fn storeb(&mut self, addr: u16, val: u8) {
-        // Handle OAM_DMA.
-        if addr == 0x4014 {
-            self.dma(val)
-        } else {
-            self.mem.storeb(addr, val)
+// The cycle count, the registers, the halt state and the NMI line; version 2 adds the I flag the
+// next IRQ poll sees
+static CPU_CHUNK_LEN: uint = 18;
+
+// Only what carries over between instructions is saved: the variant and the policies are
+// configuration, and the bus log and tracer belong to whoever attached them.
+impl<M:Mem+Snapshot> Snapshot for Cpu<M> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.chunk("CPU ", 2, |w| {
+            w.write_le_u64(self.cy);
+            w.write([self.regs.a, self.regs.x, self.regs.y, self.regs.s, self.regs.flags]);
+            w.write_le_u16(self.regs.pc);
+            let (halt, op) = match self.halt {
+                None => (0, 0),
+                Some(Jammed(op)) => (1, op),
+                Some(Trapped(op)) => (2, op),
+            };
+            w.write([halt, op, self.nmi_prev as u8]);
+            w.write_u8(match self.irq_masked { None => 0, Some(false) => 1, Some(true) => 2 });
+        });
+        self.mem.save_state(w);
+    }
+
+    fn check_state(&self, state: &StateReader) -> Result<(), ~str> {
+        match state.require("CPU ", CPU_CHUNK_LEN) {
+            Ok(()) => self.mem.check_state(state),
+            Err(e) => Err(e)
         }
     }
-}
 
-impl<M:Mem> Cpu<M> {
-    // Debugging
-    #[cfg(cpuspew)]
-    fn trace(&mut self) {
-        let mut disassembler = Disassembler {
-            pc: self.regs.pc,
-            mem: &mut self.mem
+    fn load_state(&mut self, state: &StateReader) {
+        let mut r = state.expect("CPU ");
+        self.cy = r.read_le_u64();
+        let b = r.read_bytes(5);
+        let pc = r.read_le_u16();
+        self.regs = Regs { a: b[0], x: b[1], y: b[2], s: b[3], flags: b[4], pc: pc };
+        let b = r.read_bytes(3);
+        self.halt = match b[0] {
+            0 => None,
+            1 => Some(Jammed(b[1])),
+            _ => Some(Trapped(b[1])),
+        };
+        self.nmi_prev = b[2] != 0;
+        // added in version 2
+        self.irq_masked = match r.read_byte() {
+            Some(1) => Some(false),
+            Some(2) => Some(true),
+            _ => None
         };
-        println!(
-            "%04X %-20s A:%02X X:%02X Y:%02X P:%02X SP:%02X CYC:%4u",
-            self.regs.pc as uint,
-            disassembler.disassemble(),
-            self.regs.a as uint,
-            self.regs.x as uint,
-            self.regs.y as uint,
-            self.regs.flags as uint,
-            self.regs.s as uint,
-            self.cy as uint
-        );
+        self.mem.load_state(state);
     }
-    #[cfg(not(cpuspew))]
-    fn trace(&mut self) {}
+}
 
-    // Performs DMA to the OAMDATA ($2004) register.
-    fn dma(&mut self, hi_addr: u8) {
-        for addr in range(hi_addr as uint << 8, (hi_addr + 1) as uint << 8) {
-            let val = self.loadb(addr as u16);
-            self.storeb(0x2004, val);
+// The CPU implements Mem so that bus hooks get a look at every access before memory does. In
+// cycle-accurate mode, every access is also a cycle: it's counted as it happens, and memory is
+// told which cycle it's on.
+impl<M:Mem> Mem for Cpu<M> {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        let val = self.bus_loadb(addr);
+        self.log_access(addr, val, false);
+        val
+    }
+    fn storeb(&mut self, addr: u16, val: u8) {
+        self.log_access(addr, val, true);
+        self.bus_storeb(addr, val)
+    }
+    fn irq_line(&mut self) -> bool { self.mem.irq_line() }
+    fn nmi_line(&mut self) -> bool { self.mem.nmi_line() }
+}
 
-            // FIXME: The last address sometimes takes 1 cycle, sometimes 2 -- NESdev isn't very
-            // clear on this.
-            self.cy += 2;
+impl<M:Mem> Cpu<M> {
+    // The bus itself
+    fn bus_loadb(&mut self, addr: u16) -> u8 {
+        let cy = self.cy;
+        if self.cycle_accurate {
+            self.cy += 1;
+        }
+        for h in self.hooks.mut_iter() {
+            if addr >= h.lo && addr <= h.hi {
+                match h.hook.loadb(addr) {
+                    Some(val) => return val,
+                    None => {}
+                }
+            }
+        }
+        if self.cycle_accurate { self.mem.loadb_cy(addr, cy) } else { self.mem.loadb(addr) }
+    }
+    fn bus_storeb(&mut self, addr: u16, val: u8) {
+        let cy = self.cy;
+        if self.cycle_accurate {
+            self.cy += 1;
+        }
+        for h in self.hooks.mut_iter() {
+            if addr >= h.lo && addr <= h.hi && h.hook.storeb(addr, val) {
+                return;
+            }
+        }
+        if self.cycle_accurate {
+            self.mem.storeb_cy(addr, val, cy)
+        } else {
+            self.mem.storeb(addr, val)
+        }
+    }
+    fn log_access(&mut self, addr: u16, val: u8, write: bool) {
+        let access = BusAccess { addr: addr, val: val, write: write };
+        match self.bus_log {
+            Some(ref mut log) => log.push(access.clone()),
+            None => {}
+        }
+        match self.tracer {
+            Some(ref mut tracer) => tracer.access(access),
+            None => {}
         }
     }
 
     // Memory access helpers
     /// Loads the byte at the program counter and increments the program counter.
     fn loadb_bump_pc(&mut self) -> u8 {
-        let val = self.loadb(self.regs.pc);
+        let val = match self.prefetch.take() {
+            Some(val) => val,
+            None => self.loadb(self.regs.pc)
+        };
         self.regs.pc += 1;
         val
     }
     /// Loads two bytes (little-endian) at the program counter and bumps the program counter over
     /// them.
     fn loadw_bump_pc(&mut self) -> u16 {
-        let val = self.loadw(self.regs.pc);
-        self.regs.pc += 2;
-        val
+        let lo = self.loadb_bump_pc();
+        let hi = self.loadb_bump_pc();
+        lo as u16 | (hi as u16 << 8)
+    }
+
+    // Bus cycles whose data the CPU throws away. They still reach memory, since reading or
+    // writing a soft switch flips it whatever the CPU does with the data. Dummy accesses are
+    // counted in the cycle table, while penalty reads (index fixups that cross a page, taken
+    // branches) come on top of it.
+    fn dummy_read(&mut self, addr: u16) { let _ = self.loadb(addr); }
+    fn dummy_write(&mut self, addr: u16, val: u8) { self.storeb(addr, val) }
+    // The spare cycle of a read-modify-write: the NMOS 6502 writes the unmodified value back,
+    // while the 65C02 reads it again.
+    fn rmw_dummy(&mut self, addr: u16, old: u8) {
+        if self.cmos() { self.dummy_read(addr) } else { self.dummy_write(addr, old) }
+    }
+    fn penalty_read(&mut self, addr: u16) {
+        self.penalty += 1;
+        self.dummy_read(addr);
+    }
+    fn stack_dummy_read(&mut self) {
+        let addr = 0x100 + self.regs.s as u16;
+        self.dummy_read(addr)
+    }
+
+    // Charges for an instruction or interrupt sequence that began on cycle `start` and takes
+    // `cycles` in all. In cycle-accurate mode the bus cycles have already been counted, so this
+    // only adds the internal ones.
+    fn finish(&mut self, start: Cycles, cycles: Cycles) {
+        if self.cy < start + cycles {
+            self.cy = start + cycles;
+        }
     }
 
     // Stack helpers
@@ -383,22 +769,19 @@ impl<M:Mem> Cpu<M> {
         self.storeb(0x100 + self.regs.s as u16, val);
         self.regs.s -= 1;
     }
+    // Words go a byte at a time, high byte first, so they wrap around within page 1.
     fn pushw(&mut self, val: u16) {
-        // FIXME: Is this correct? FCEU has two self.storeb()s here. Might have different
-        // semantics...
-        self.storew(0x100 + (self.regs.s - 1) as u16, val);
-        self.regs.s -= 2;
+        self.pushb((val >> 8) as u8);
+        self.pushb((val & 0xff) as u8);
     }
     fn popb(&mut self) -> u8 {
-        let val = self.loadb(0x100 + self.regs.s as u16 + 1);
         self.regs.s += 1;
-        val
+        self.loadb(0x100 + self.regs.s as u16)
     }
     fn popw(&mut self) -> u16 {
-        // FIXME: See comment in pushw().
-        let val = self.loadw(0x100 + self.regs.s as u16 + 1);
-        self.regs.s += 2;
-        val
+        let lo = self.popb();
+        let hi = self.popb();
+        lo as u16 | (hi as u16 << 8)
     }
 
     // Flag helpers
@@ -410,9 +793,11 @@ impl<M:Mem> Cpu<M> {
             self.regs.flags &= !flag;
         }
     }
+    // B and the unused bit aren't stored in P: they only exist in copies pushed on the stack,
+    // where the unused bit is always set and B tells BRK and PHP apart from IRQ and NMI. We
+    // keep bit 5 set and B clear so that P reads back the way PHP without B would push it.
     fn set_flags(&mut self, val: u8) {
-        // Flags get munged in a strange way relating to the unused bit 5 on the NES.
-        self.regs.flags = (val | 0x30) - 0x10;
+        self.regs.flags = (val | UNUSED_FLAG) & !BREAK_FLAG;
     }
     fn set_zn(&mut self, val: u8) -> u8 {
         self.set_flag(ZERO_FLAG, val == 0);
@@ -427,29 +812,40 @@ impl<M:Mem> Cpu<M> {
         MemoryAddressingMode(self.loadb_bump_pc() as u16)
     }
     fn zero_page_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode((self.loadb_bump_pc() + self.regs.x) as u16)
+        let base = self.loadb_bump_pc();
+        self.dummy_read(base as u16);
+        MemoryAddressingMode((base + self.regs.x) as u16)
     }
     fn zero_page_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode((self.loadb_bump_pc() + self.regs.y) as u16)
+        let base = self.loadb_bump_pc();
+        self.dummy_read(base as u16);
+        MemoryAddressingMode((base + self.regs.y) as u16)
     }
     fn absolute(&mut self) -> MemoryAddressingMode {
         MemoryAddressingMode(self.loadw_bump_pc())
     }
-    fn absolute_x(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode(self.loadw_bump_pc() + self.regs.x as u16)
+    fn absolute_x(&mut self) -> IndexedAddressingMode {
+        let base = self.loadw_bump_pc();
+        IndexedAddressingMode { base: base, addr: base + self.regs.x as u16 }
+    }
+    fn absolute_y(&mut self) -> IndexedAddressingMode {
+        let base = self.loadw_bump_pc();
+        IndexedAddressingMode { base: base, addr: base + self.regs.y as u16 }
     }
-    fn absolute_y(&mut self) -> MemoryAddressingMode {
-        MemoryAddressingMode(self.loadw_bump_pc() + self.regs.y as u16)
+    fn zero_page_indirect(&mut self) -> MemoryAddressingMode {
+        let val = self.loadb_bump_pc();
+        MemoryAddressingMode(self.loadw_zp(val))
     }
     fn indexed_indirect_x(&mut self) -> MemoryAddressingMode {
         let val = self.loadb_bump_pc();
+        self.dummy_read(val as u16);
         let addr = self.loadw_zp(val + self.regs.x);
         MemoryAddressingMode(addr)
     }
-    fn indirect_indexed_y(&mut self) -> MemoryAddressingMode {
+    fn indirect_indexed_y(&mut self) -> IndexedAddressingMode {
         let val = self.loadb_bump_pc();
-        let addr = self.loadw_zp(val) + self.regs.y as u16;
-        MemoryAddressingMode(addr)
+        let base = self.loadw_zp(val);
+        IndexedAddressingMode { base: base, addr: base + self.regs.y as u16 }
     }
 
     //
@@ -479,6 +875,18 @@ impl<M:Mem> Cpu<M> {
     #[inline(always)]
     fn adc<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = am.load(self);
+        self.adc_base(val)
+    }
+    #[inline(always)]
+    fn sbc<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        self.sbc_base(val)
+    }
+    fn adc_base(&mut self, val: u8) {
+        if self.get_flag(DECIMAL_FLAG) {
+            return self.adc_decimal(val);
+        }
+
         let mut result = self.regs.a as u32 + val as u32;
         if self.get_flag(CARRY_FLAG) {
             result += 1;
@@ -491,11 +899,10 @@ impl<M:Mem> Cpu<M> {
                       (self.regs.a ^ val) & 0x80 == 0 && (self.regs.a ^ result) & 0x80 == 0x80);
         self.regs.a = self.set_zn(result);
     }
-    #[inline(always)]
-    fn sbc<AM:AddressingMode<M>>(&mut self, am: AM) {
-        let val = am.load(self);
+    fn sbc_base(&mut self, val: u8) {
+        let borrow = !self.get_flag(CARRY_FLAG);
         let mut result = self.regs.a as u32 - val as u32;
-        if !self.get_flag(CARRY_FLAG) {
+        if borrow {
             result -= 1;
         }
 
@@ -504,12 +911,89 @@ impl<M:Mem> Cpu<M> {
         let result = result as u8;
         self.set_flag(OVERFLOW_FLAG,
                       (self.regs.a ^ result) & 0x80 != 0 && (self.regs.a ^ val) & 0x80 == 0x80);
-        self.regs.a = self.set_zn(result);
+        let result = self.set_zn(result);
+
+        // The NMOS 6502 leaves all flags as the binary subtraction set them, even in decimal
+        // mode; only the accumulator gets the BCD result.
+        self.regs.a = if self.get_flag(DECIMAL_FLAG) {
+            self.sbc_decimal(val, borrow)
+        } else {
+            result
+        };
+
+        if self.get_flag(DECIMAL_FLAG) && self.cmos() {
+            // ...but the 65C02 takes an extra cycle to set N and Z from the BCD result.
+            self.regs.a = self.set_zn(self.regs.a);
+            self.penalty += 1;
+        }
+    }
+
+    // Decimal mode arithmetic. This follows the NMOS 6502: for ADC, Z reflects the binary sum,
+    // while N and V are taken from the intermediate result after the low nibble has been adjusted
+    // but before the high nibble has.
+    fn adc_decimal(&mut self, val: u8) {
+        let a = self.regs.a as uint;
+        let b = val as uint;
+        let c = if self.get_flag(CARRY_FLAG) { 1 } else { 0 };
+
+        let mut lo = (a & 0x0f) + (b & 0x0f) + c;
+        if lo > 0x09 {
+            lo += 0x06;
+        }
+        let mut hi = (a >> 4) + (b >> 4) + if lo > 0x0f { 1 } else { 0 };
+
+        self.set_flag(ZERO_FLAG, (a + b + c) & 0xff == 0);
+        self.set_flag(NEGATIVE_FLAG, (hi & 0x08) != 0);
+        self.set_flag(OVERFLOW_FLAG, (a ^ b) & 0x80 == 0 && (a ^ (hi << 4)) & 0x80 != 0);
+
+        if hi > 0x09 {
+            hi += 0x06;
+        }
+        self.set_flag(CARRY_FLAG, hi > 0x0f);
+        self.regs.a = ((hi << 4) | (lo & 0x0f)) as u8;
+
+        if self.cmos() {
+            // The 65C02 spends an extra cycle to set N and Z from the decimal result.
+            self.regs.a = self.set_zn(self.regs.a);
+            self.penalty += 1;
+        }
+    }
+    fn sbc_decimal(&self, val: u8, borrow: bool) -> u8 {
+        let a = self.regs.a as int;
+        let b = val as int;
+        let borrow = if borrow { 1 } else { 0 };
+
+        let mut lo = (a & 0x0f) - (b & 0x0f) - borrow;
+        if self.cmos() {
+            // The 65C02 adjusts the whole binary difference, which only differs from the NMOS
+            // result for invalid BCD operands.
+            let mut result = a - b - borrow;
+            if result < 0 {
+                result -= 0x60;
+            }
+            if lo < 0 {
+                result -= 0x06;
+            }
+            return (result & 0xff) as u8;
+        }
+
+        let mut hi = (a >> 4) - (b >> 4);
+        if lo < 0 {
+            lo -= 0x06;
+            hi -= 1;
+        }
+        if hi < 0 {
+            hi -= 0x06;
+        }
+        (((hi << 4) | (lo & 0x0f)) & 0xff) as u8
     }
 
     // Comparisons
     fn cmp_base<AM:AddressingMode<M>>(&mut self, x: u8, am: AM) {
         let y = am.load(self);
+        self.compare(x, y)
+    }
+    fn compare(&mut self, x: u8, y: u8) {
         let result = x as u32 - y as u32;
         self.set_flag(CARRY_FLAG, (result & 0x100) == 0);
         let _ = self.set_zn(result as u8);
@@ -539,27 +1023,32 @@ impl<M:Mem> Cpu<M> {
     }
 
     // Shifts and rotates
+    fn load_shift<AM:AddressingMode<M>>(&mut self, am: &AM) -> u8 {
+        // Unlike INC and DEC, the 65C02's abs,X shifts only spend the index fixup cycle when
+        // they cross a page, just like a load.
+        if self.cmos() { am.load(self) } else { am.load_rmw(self) }
+    }
     fn shl_base<AM:AddressingMode<M>>(&mut self, lsb: bool, am: AM) {
-        let val = am.load(self);
+        let val = self.load_shift(&am);
         let new_carry = (val & 0x80) != 0;
         let mut result = val << 1;
         if lsb {
             result |= 1;
         }
         self.set_flag(CARRY_FLAG, new_carry);
-        let val = self.set_zn(result as u8);
-        am.store(self, val)
+        let result = self.set_zn(result as u8);
+        am.store_rmw(self, val, result)
     }
     fn shr_base<AM:AddressingMode<M>>(&mut self, msb: bool, am: AM) {
-        let val = am.load(self);
+        let val = self.load_shift(&am);
         let new_carry = (val & 0x1) != 0;
         let mut result = val >> 1;
         if msb {
             result |= 0x80;
         }
         self.set_flag(CARRY_FLAG, new_carry);
-        let val = self.set_zn(result as u8);
-        am.store(self, val)
+        let result = self.set_zn(result as u8);
+        am.store_rmw(self, val, result)
     }
     fn rol<AM:AddressingMode<M>>(&mut self, am: AM) {
         let val = self.get_flag(CARRY_FLAG);
@@ -574,14 +1063,14 @@ impl<M:Mem> Cpu<M> {
 
     // Increments and decrements
     fn inc<AM:AddressingMode<M>>(&mut self, am: AM) {
-        let val = am.load(self);
-        let val = self.set_zn(val + 1);
-        am.store(self, val)
+        let val = am.load_rmw(self);
+        let result = self.set_zn(val + 1);
+        am.store_rmw(self, val, result)
     }
     fn dec<AM:AddressingMode<M>>(&mut self, am: AM) {
-        let val = am.load(self);
-        let val = self.set_zn(val - 1);
-        am.store(self, val)
+        let val = am.load_rmw(self);
+        let result = self.set_zn(val - 1);
+        am.store_rmw(self, val, result)
     }
     fn inx(&mut self) { self.regs.x = self.set_zn(self.regs.x + 1) }
     fn dex(&mut self) { self.regs.x = self.set_zn(self.regs.x - 1) }
@@ -609,7 +1098,14 @@ impl<M:Mem> Cpu<M> {
     fn bra_base(&mut self, cond: bool) {
         let disp = self.loadb_bump_pc() as i8;
         if cond {
-            self.regs.pc = (self.regs.pc as i32 + disp as i32) as u16;
+            // A taken branch spends a cycle reading the next opcode, and if it lands on a
+            // different page, another reading from the wrong page while it fixes up PCH.
+            let target = (self.regs.pc as i32 + disp as i32) as u16;
+            self.penalty_read(self.regs.pc);
+            if (self.regs.pc ^ target) & 0xff00 != 0 {
+                self.penalty_read((self.regs.pc & 0xff00) | (target & 0x00ff));
+            }
+            self.regs.pc = target;
         }
     }
     fn bpl(&mut self) {
@@ -650,27 +1146,44 @@ impl<M:Mem> Cpu<M> {
     fn jmpi(&mut self) {
         let addr = self.loadw_bump_pc();
 
-        // Replicate the famous CPU bug...
+        // Replicate the famous CPU bug... which the 65C02 fixed.
         let lo = self.loadb(addr);
-        let hi = self.loadb((addr & 0xff00) | ((addr + 1) & 0x00ff));
+        let hi = if self.cmos() {
+            self.loadb(addr + 1)
+        } else {
+            self.loadb((addr & 0xff00) | ((addr + 1) & 0x00ff))
+        };
 
         self.regs.pc = (hi as u16 << 8) | lo as u16;
     }
 
     // Procedure calls
     fn jsr(&mut self) {
-        let addr = self.loadw_bump_pc();
-        self.pushw(self.regs.pc - 1);
-        self.regs.pc = addr;
+        // The high byte of the target is fetched last, after the return address (which points
+        // at it) has been pushed.
+        let lo = self.loadb_bump_pc();
+        self.stack_dummy_read();
+        self.pushw(self.regs.pc);
+        let hi = self.loadb(self.regs.pc);
+        self.regs.pc = lo as u16 | (hi as u16 << 8);
+    }
+    fn rts(&mut self) {
+        self.stack_dummy_read();
+        let addr = self.popw();
+        self.dummy_read(addr);
+        self.regs.pc = addr + 1
     }
-    fn rts(&mut self) { self.regs.pc = self.popw() + 1 }
     fn brk(&mut self) {
         self.pushw(self.regs.pc + 1);
-        self.pushb(self.regs.flags);    // FIXME: FCEU sets BREAK_FLAG and U_FLAG here, why?
+        self.pushb(self.regs.flags | BREAK_FLAG | UNUSED_FLAG);
         self.set_flag(IRQ_FLAG, true);
+        if self.cmos() {
+            self.set_flag(DECIMAL_FLAG, false);
+        }
         self.regs.pc = self.loadw(BRK_VECTOR);
     }
     fn rti(&mut self) {
+        self.stack_dummy_read();
         let flags = self.popb();
         self.set_flags(flags);
         self.regs.pc = self.popw(); // NB: no + 1
@@ -679,11 +1192,13 @@ impl<M:Mem> Cpu<M> {
     // Stack operations
     fn pha(&mut self) { self.pushb(self.regs.a) }
     fn pla(&mut self) {
+        self.stack_dummy_read();
         let val = self.popb();
         self.regs.a = self.set_zn(val)
     }
-    fn php(&mut self) { self.pushb(self.regs.flags | BREAK_FLAG) }
+    fn php(&mut self) { self.pushb(self.regs.flags | BREAK_FLAG | UNUSED_FLAG) }
     fn plp(&mut self) {
+        self.stack_dummy_read();
         let val = self.popb();
         self.set_flags(val)
     }
@@ -691,36 +1206,448 @@ impl<M:Mem> Cpu<M> {
     // No operation
     fn nop(&mut self) {}
 
-    // The main fetch-and-decode routine
-    pub fn step(&mut self) {
-        self.trace();
+    // 65C02 instructions
+    fn cmos(&self) -> bool { self.variant == Cmos65C02 }
+    fn stz<AM:AddressingMode<M>>(&mut self, am: AM) { am.store(self, 0) }
+    fn trb<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load_rmw(self);
+        self.set_flag(ZERO_FLAG, (val & self.regs.a) == 0);
+        am.store_rmw(self, val, val & !self.regs.a)
+    }
+    fn tsb<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load_rmw(self);
+        self.set_flag(ZERO_FLAG, (val & self.regs.a) == 0);
+        am.store_rmw(self, val, val | self.regs.a)
+    }
+    fn bit_imm<AM:AddressingMode<M>>(&mut self, am: AM) {
+        // BIT #imm only affects Z; there's no memory operand to take N and V from.
+        let val = am.load(self);
+        self.set_flag(ZERO_FLAG, (val & self.regs.a) == 0);
+    }
+    fn bra(&mut self) { self.bra_base(true) }
+    fn jmpix(&mut self) {
+        let addr = self.loadw_bump_pc() + self.regs.x as u16;
+        self.regs.pc = self.loadw(addr);
+    }
+    fn phx(&mut self) { self.pushb(self.regs.x) }
+    fn phy(&mut self) { self.pushb(self.regs.y) }
+    fn plx(&mut self) {
+        self.stack_dummy_read();
+        let val = self.popb();
+        self.regs.x = self.set_zn(val)
+    }
+    fn ply(&mut self) {
+        self.stack_dummy_read();
+        let val = self.popb();
+        self.regs.y = self.set_zn(val)
+    }
+    fn nop_read<AM:AddressingMode<M>>(&mut self, am: AM) { let _ = am.load(self); }
+
+    // NMOS undocumented instructions. The read-modify-write ones combine a shift or increment
+    // with an ALU operation on the result.
+    fn slo<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        self.set_flag(CARRY_FLAG, (old & 0x80) != 0);
+        let val = old << 1;
+        am.store_rmw(self, old, val);
+        self.regs.a = self.set_zn(self.regs.a | val)
+    }
+    fn rla<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        let carry = if self.get_flag(CARRY_FLAG) { 1 } else { 0 };
+        self.set_flag(CARRY_FLAG, (old & 0x80) != 0);
+        let val = (old << 1) | carry;
+        am.store_rmw(self, old, val);
+        self.regs.a = self.set_zn(self.regs.a & val)
+    }
+    fn sre<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        self.set_flag(CARRY_FLAG, (old & 0x01) != 0);
+        let val = old >> 1;
+        am.store_rmw(self, old, val);
+        self.regs.a = self.set_zn(self.regs.a ^ val)
+    }
+    fn rra<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        let carry = if self.get_flag(CARRY_FLAG) { 0x80 } else { 0 };
+        self.set_flag(CARRY_FLAG, (old & 0x01) != 0);
+        let val = (old >> 1) | carry;
+        am.store_rmw(self, old, val);
+        self.adc_base(val)
+    }
+    fn sax<AM:AddressingMode<M>>(&mut self, am: AM) { am.store(self, self.regs.a & self.regs.x) }
+    fn lax<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        self.regs.a = self.set_zn(val);
+        self.regs.x = val
+    }
+    fn dcp<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        let val = old - 1;
+        am.store_rmw(self, old, val);
+        self.compare(self.regs.a, val)
+    }
+    fn isc<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let old = am.load_rmw(self);
+        let val = old + 1;
+        am.store_rmw(self, old, val);
+        self.sbc_base(val)
+    }
+    fn anc<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        self.regs.a = self.set_zn(val);
+        self.set_flag(CARRY_FLAG, (val & 0x80) != 0)
+    }
+    fn alr<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        self.set_flag(CARRY_FLAG, (val & 0x01) != 0);
+        self.regs.a = self.set_zn(val >> 1)
+    }
+    fn arr<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.a;
+        let carry = self.get_flag(CARRY_FLAG);
+        let result = (val >> 1) | if carry { 0x80 } else { 0 };
+        let result = self.set_zn(result);
+        if !self.get_flag(DECIMAL_FLAG) {
+            self.set_flag(CARRY_FLAG, (result & 0x40) != 0);
+            self.set_flag(OVERFLOW_FLAG, ((result >> 6) ^ (result >> 5)) & 1 != 0);
+            self.regs.a = result;
+            return;
+        }
+
+        // In decimal mode the rotated value gets a BCD fixup, with N, Z and V still taken from
+        // the plain rotate.
+        self.set_flag(OVERFLOW_FLAG, (val ^ result) & 0x40 != 0);
+        let mut result = result;
+        if (val & 0x0f) + (val & 0x01) > 0x05 {
+            result = (result & 0xf0) | ((result + 0x06) & 0x0f);
+        }
+        let fixup = (val as uint & 0xf0) + (val as uint & 0x10) > 0x50;
+        self.set_flag(CARRY_FLAG, fixup);
+        if fixup {
+            result += 0x60;
+        }
+        self.regs.a = result
+    }
+    fn sbx<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self);
+        let x = self.regs.a & self.regs.x;
+        self.set_flag(CARRY_FLAG, x >= val);
+        self.regs.x = self.set_zn(x - val)
+    }
+    fn las<AM:AddressingMode<M>>(&mut self, am: AM) {
+        let val = am.load(self) & self.regs.s;
+        self.regs.a = self.set_zn(val);
+        self.regs.x = val;
+        self.regs.s = val
+    }
+
+    // The unstable ones. For the SH* stores, the value is ANDed with the high byte of the base
+    // address plus one, and when indexing crosses a page that value replaces the high byte of
+    // the address.
+    fn unstable(&mut self, op: u8) -> bool {
+        match self.unstable_policy {
+            EmulateUnstable => true,
+            TrapUnstable => {
+                self.halt = Some(Trapped(op));
+                false
+            }
+            WarnUnstable => {
+                warn!("unstable instruction {:02x} treated as NOP", op);
+                false
+            }
+        }
+    }
+    fn ane<AM:AddressingMode<M>>(&mut self, op: u8, am: AM) {
+        let val = am.load(self);
+        if self.unstable(op) {
+            self.regs.a = self.set_zn((self.regs.a | 0xee) & self.regs.x & val)
+        }
+    }
+    fn lxa<AM:AddressingMode<M>>(&mut self, op: u8, am: AM) {
+        let val = am.load(self);
+        if self.unstable(op) {
+            self.regs.a = self.set_zn((self.regs.a | 0xee) & val);
+            self.regs.x = self.regs.a
+        }
+    }
+    fn sh_store(&mut self, val: u8, am: IndexedAddressingMode) {
+        let val = val & ((am.base >> 8) as u8 + 1);
+        let addr = if (am.base ^ am.addr) & 0xff00 != 0 {
+            (val as u16 << 8) | (am.addr & 0xff)
+        } else {
+            am.addr
+        };
+        self.dummy_read(am.unfixed());
+        self.storeb(addr, val)
+    }
+    fn sha(&mut self, op: u8, am: IndexedAddressingMode) {
+        if self.unstable(op) {
+            self.sh_store(self.regs.a & self.regs.x, am)
+        }
+    }
+    fn shx(&mut self, op: u8, am: IndexedAddressingMode) {
+        if self.unstable(op) {
+            self.sh_store(self.regs.x, am)
+        }
+    }
+    fn shy(&mut self, op: u8, am: IndexedAddressingMode) {
+        if self.unstable(op) {
+            self.sh_store(self.regs.y, am)
+        }
+    }
+    fn tas(&mut self, op: u8, am: IndexedAddressingMode) {
+        if self.unstable(op) {
+            self.regs.s = self.regs.a & self.regs.x;
+            self.sh_store(self.regs.s, am)
+        }
+    }
 
+    fn jam(&mut self, op: u8) { self.halt = Some(Jammed(op)) }
+
+    /// The main fetch-and-decode routine. Returns whether an instruction ran, rather than an
+    /// interrupt being taken or the CPU being halted.
+    pub fn step(&mut self) -> bool {
+        if self.halt.is_some() {
+            // Time keeps passing for a halted CPU, and the NMI line is still watched, so that an
+            // edge while halted isn't taken as one when the CPU starts again.
+            self.cy += 1;
+            self.nmi_prev = self.mem.nmi_line();
+            return false;
+        }
+
+        // The interrupt lines are polled between instructions. NMI is edge-triggered, so it's
+        // taken once when the line goes high; IRQ is level-triggered, and is taken for as long
+        // as the line is held and the I flag is clear. CLI, SEI and PLP change I after the poll
+        // during their last cycle, so the poll after one of them sees I as it was before it.
+        let nmi = self.mem.nmi_line();
+        let nmi_edge = nmi && !self.nmi_prev;
+        self.nmi_prev = nmi;
+        let irq_masked = replace(&mut self.irq_masked, None).unwrap_or(self.get_flag(IRQ_FLAG));
+        if nmi_edge {
+            self.nmi();
+            return false;
+        }
+        if self.mem.irq_line() && !irq_masked {
+            self.interrupt(BRK_VECTOR);
+            return false;
+        }
+
+        match self.tracer {
+            Some(ref mut tracer) => tracer.begin(&self.regs, self.cy),
+            None => {}
+        }
+
+        let start = self.cy;
+        self.penalty = 0;
+        let pc = self.regs.pc;
+        let masked_before = self.get_flag(IRQ_FLAG);
         let op = self.loadb_bump_pc();
+        // Cycle 2 always reads the byte after the opcode, whether or not it's an operand.
+        let next = self.loadb(self.regs.pc);
+        self.prefetch = Some(next);
         decode_op!(op, self);
+        self.prefetch = None;
+        match self.tracer {
+            Some(ref mut tracer) => tracer.end(),
+            None => {}
+        }
 
-        self.cy += CYCLE_TABLE[op] as Cycles;
-    }
+        if self.halt.is_some() {
+            self.regs.pc = pc;
+            return false;
+        }
 
-    /// External interfaces
-    pub fn reset(&mut self) { self.regs.pc = self.loadw(RESET_VECTOR); }
+        let cycles = if self.cmos() { CYCLE_TABLE_65C02[op] } else { CYCLE_TABLE[op] };
+        self.finish(start, cycles as Cycles + self.penalty);
+        if op == CLI_OPCODE || op == SEI_OPCODE || op == PLP_OPCODE {
+            self.irq_masked = Some(masked_before);
+        }
+        true
+    }
 
-    pub fn nmi(&mut self) {
-        self.pushw(self.regs.pc);
-        self.pushb(self.regs.flags);
-        self.regs.pc = self.loadw(NMI_VECTOR);
+    // External interfaces
+
+    /// The reset sequence: like an interrupt, but the three pushes are turned into reads, so S
+    /// drops by 3 while nothing gets written. Other registers are left alone, except that I is
+    /// set (and D cleared, on the 65C02).
+    pub fn reset(&mut self) {
+        let start = self.cy;
+        self.halt = None;
+        self.dummy_read(self.regs.pc);
+        self.dummy_read(self.regs.pc);
+        for _ in range(0, 3) {
+            self.stack_dummy_read();
+            self.regs.s -= 1;
+        }
+        self.set_flag(IRQ_FLAG, true);
+        if self.cmos() {
+            self.set_flag(DECIMAL_FLAG, false);
+        }
+        self.regs.pc = self.loadw(RESET_VECTOR);
+        self.finish(start, INTERRUPT_CYCLES);
     }
 
+    /// Takes a non-maskable interrupt immediately. Memory that raises NMI through
+    /// `Mem::nmi_line` doesn't need to call this.
+    pub fn nmi(&mut self) { self.interrupt(NMI_VECTOR) }
+
+    /// Takes an interrupt request immediately, unless the I flag masks it. Memory that raises IRQ
+    /// through `Mem::irq_line` doesn't need to call this.
     pub fn irq(&mut self) {
-        if self.get_flag(IRQ_FLAG) {
-            return;
+        if !self.get_flag(IRQ_FLAG) {
+            self.interrupt(BRK_VECTOR)
         }
+    }
 
+    // The hardware interrupt sequence. Unlike BRK, this pushes P with the B flag clear.
+    fn interrupt(&mut self, vector: u16) {
+        let start = self.cy;
+        self.dummy_read(self.regs.pc);
+        self.dummy_read(self.regs.pc);
         self.pushw(self.regs.pc);
-        self.pushb(self.regs.flags);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+        self.pushb((self.regs.flags & !BREAK_FLAG) | UNUSED_FLAG);
+        self.set_flag(IRQ_FLAG, true);
+        if self.cmos() {
+            self.set_flag(DECIMAL_FLAG, false);
+        }
+        self.regs.pc = self.loadw(vector);
+        self.finish(start, INTERRUPT_CYCLES);
+    }
+
+    pub fn variant(&self) -> CpuVariant { self.variant }
+
+    //
+    // State inspection and mutation
+    //
+
+    pub fn regs(&self) -> Regs { self.regs.clone() }
+    pub fn set_regs(&mut self, regs: Regs) { self.regs = regs }
+
+    pub fn a(&self) -> u8 { self.regs.a }
+    pub fn x(&self) -> u8 { self.regs.x }
+    pub fn y(&self) -> u8 { self.regs.y }
+    pub fn s(&self) -> u8 { self.regs.s }
+    pub fn p(&self) -> u8 { self.regs.flags }
+    pub fn pc(&self) -> u16 { self.regs.pc }
+    pub fn set_a(&mut self, val: u8) { self.regs.a = val }
+    pub fn set_x(&mut self, val: u8) { self.regs.x = val }
+    pub fn set_y(&mut self, val: u8) { self.regs.y = val }
+    pub fn set_s(&mut self, val: u8) { self.regs.s = val }
+    pub fn set_p(&mut self, val: u8) { self.set_flags(val) }
+    pub fn set_pc(&mut self, val: u16) { self.regs.pc = val }
+
+    /// The number of cycles run since the CPU was created.
+    pub fn cycles(&self) -> Cycles { self.cy }
+    pub fn set_cycles(&mut self, cy: Cycles) { self.cy = cy }
+
+    /// Borrows the memory bus. Accesses made through it bypass the bus hooks.
+    pub fn mem<'a>(&'a self) -> &'a M { &self.mem }
+    pub fn mem_mut<'a>(&'a mut self) -> &'a mut M { &mut self.mem }
+
+    pub fn set_unstable_policy(&mut self, policy: UnstableOpPolicy) {
+        self.unstable_policy = policy;
+    }
+
+    /// The CPU always makes every bus access the 6502 does (including the dummy ones), in order.
+    /// In cycle-accurate mode, memory is called through `Mem::loadb_cy` and `Mem::storeb_cy` with
+    /// the cycle each one happens on; otherwise each instruction runs all at once and then adds
+    /// its cycles.
+    pub fn set_cycle_accurate(&mut self, on: bool) { self.cycle_accurate = on }
+    pub fn cycle_accurate(&self) -> bool { self.cycle_accurate }
+
+    //
+    // Run loops. Each stops at an instruction boundary, so it may overrun its cycle budget by
+    // part of an instruction.
+    //
+
+    /// Runs until at least `cycles` more cycles have gone by.
+    pub fn run_cycles(&mut self, cycles: Cycles) -> StopReason {
+        self.run_until(cycles, |_| false)
+    }
+
+    /// Runs until the PC is `pc` (which may be right away), for at most `max_cycles`.
+    pub fn run_until_pc(&mut self, pc: u16, max_cycles: Cycles) -> StopReason {
+        match self.run_until(max_cycles, |cpu| cpu.regs.pc == pc) {
+            ConditionMet => ReachedPc,
+            reason => reason
+        }
+    }
+
+    /// Runs until `pred` returns true, for at most `max_cycles`. The predicate is checked
+    /// before every instruction, including the first.
+    pub fn run_until(&mut self, max_cycles: Cycles, pred: |&Cpu<M>| -> bool) -> StopReason {
+        let end = self.cy + max_cycles;
+        loop {
+            if pred(&*self) {
+                return ConditionMet;
+            }
+            match self.halt {
+                Some(halt) => return Halted(halt),
+                None => {}
+            }
+            if self.cy >= end {
+                return RanCycles;
+            }
+            self.step();
+        }
+    }
+
+    /// Returns why the CPU has stopped, or None if it's running.
+    pub fn halted(&self) -> Option<Halt> { self.halt }
+
+    /// Starts or stops logging the CPU's bus accesses, for debuggers to check against their
+    /// watchpoints.
+    pub fn set_bus_log(&mut self, on: bool) {
+        self.bus_log = if on { Some(~[]) } else { None };
+    }
+
+    /// Returns the accesses logged since the last call, oldest first.
+    pub fn take_bus_log(&mut self) -> ~[BusAccess] {
+        match self.bus_log {
+            Some(ref mut log) => replace(log, ~[]),
+            None => ~[]
+        }
+    }
+
+    /// Attaches a tracer, or with None detaches it, returning the one that was attached.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer<'a>(&'a mut self) -> Option<&'a mut Tracer> { self.tracer.as_mut() }
+
+    /// Registers a hook for CPU accesses to `lo..hi` (inclusive). Hooks are consulted in the
+    /// order they were added, and the first one to handle an access wins.
+    pub fn add_hook(&mut self, lo: u16, hi: u16, hook: ~BusHook) {
+        self.hooks.push(Hook { lo: lo, hi: hi, hook: hook });
     }
 
     /// The constructor.
-    pub fn new(mem: M) -> Cpu<M> { Cpu { cy: 0, regs: Regs::new(), mem: mem } }
+    pub fn new(mem: M) -> Cpu<M> { Cpu::with_variant(mem, Nmos6502) }
+
+    pub fn with_variant(mem: M, variant: CpuVariant) -> Cpu<M> {
+        Cpu::with_state(mem, variant, Regs::new(), 0)
+    }
+
+    /// Builds a CPU in an arbitrary state, e.g. to run a single instruction from a test vector.
+    pub fn with_state(mem: M, variant: CpuVariant, regs: Regs, cy: Cycles) -> Cpu<M> {
+        Cpu {
+            cy: cy,
+            regs: regs,
+            mem: mem,
+            hooks: ~[],
+            variant: variant,
+            unstable_policy: EmulateUnstable,
+            halt: None,
+            nmi_prev: false,
+            irq_masked: None,
+            cycle_accurate: false,
+            prefetch: None,
+            penalty: 0,
+            bus_log: None,
+            tracer: None,
+        }
+    }
 }
 
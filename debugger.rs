@@ -0,0 +1,336 @@
+//
+// Debugger core
+//
+// Wraps a `Cpu` with breakpoints, watchpoints and stepping commands. Watchpoints are checked
+// against the CPU's bus log, so they see exactly the accesses memory does, dummy ones included.
+// With rewind enabled, it can also step backwards.
+//
+
+use cpu::{Cpu, Cycles, Halt};
+use mem::{Mem, BusAccess};
+use rewind::Rewind;
+use state::Snapshot;
+
+static JSR_OPCODE: u8 = 0x20;
+static RTS_OPCODE: u8 = 0x60;
+static RTI_OPCODE: u8 = 0x40;
+
+/// The soft switches live in $C000-$C0FF.
+pub static SOFT_SWITCH_LO: u16 = 0xc000;
+pub static SOFT_SWITCH_HI: u16 = 0xc0ff;
+
+#[deriving(Eq, Clone)]
+pub enum Register {
+    RegA,
+    RegX,
+    RegY,
+    RegS,
+    RegP,
+    RegPC,
+}
+
+/// When a breakpoint fires, in addition to the PC matching.
+#[deriving(Eq, Clone)]
+pub enum Condition {
+    Always,
+    /// The register holds the value.
+    RegisterIs(Register, u16),
+    /// The memory location holds the value, as read by `Mem::peekb`.
+    MemoryIs(u16, u8),
+}
+
+#[deriving(Eq, Clone)]
+pub enum WatchKind {
+    WatchRead,
+    WatchWrite,
+    WatchAccess,
+}
+
+pub struct Breakpoint {
+    pub id: uint,
+    pub addr: u16,
+    pub condition: Condition,
+    /// How many times execution has stopped here.
+    pub hits: uint,
+}
+
+pub struct Watchpoint {
+    pub id: uint,
+    pub lo: u16,
+    pub hi: u16,
+    pub kind: WatchKind,
+    /// How many matching accesses there have been.
+    pub hits: uint,
+}
+
+/// Why a debugger command returned.
+#[deriving(Eq, Clone)]
+pub enum DebugEvent {
+    /// The command finished: the instruction was stepped, or the subroutine stepped over or out
+    /// of returned.
+    StepDone,
+    /// About to execute the instruction at a breakpoint, with the given id.
+    HitBreakpoint(uint),
+    /// The instruction just executed made an access that the watchpoint with the given id
+    /// matches. Only the first matching access is reported.
+    HitWatchpoint(uint, BusAccess),
+    /// The CPU halted.
+    CpuHalted(Halt),
+    /// The cycle budget ran out.
+    OutOfCycles,
+}
+
+impl Condition {
+    fn holds<M:Mem>(&self, cpu: &mut Cpu<M>) -> bool {
+        match *self {
+            Always => true,
+            RegisterIs(reg, val) => {
+                let actual = match reg {
+                    RegA => cpu.a() as u16,
+                    RegX => cpu.x() as u16,
+                    RegY => cpu.y() as u16,
+                    RegS => cpu.s() as u16,
+                    RegP => cpu.p() as u16,
+                    RegPC => cpu.pc(),
+                };
+                actual == val
+            }
+            MemoryIs(addr, val) => cpu.mem_mut().peekb(addr) == val,
+        }
+    }
+}
+
+impl Watchpoint {
+    fn matches(&self, access: &BusAccess) -> bool {
+        let kind = match self.kind {
+            WatchRead => !access.write,
+            WatchWrite => access.write,
+            WatchAccess => true,
+        };
+        kind && access.addr >= self.lo && access.addr <= self.hi
+    }
+}
+
+pub struct Debugger<M> {
+    cpu: Cpu<M>,
+    breakpoints: ~[Breakpoint],
+    watchpoints: ~[Watchpoint],
+    next_id: uint,
+    rewind: Option<Rewind<M>>,
+}
+
+impl<M:Mem> Debugger<M> {
+    pub fn new(cpu: Cpu<M>) -> Debugger<M> {
+        let mut cpu = cpu;
+        cpu.set_bus_log(true);
+        Debugger { cpu: cpu, breakpoints: ~[], watchpoints: ~[], next_id: 1, rewind: None }
+    }
+
+    pub fn cpu<'a>(&'a self) -> &'a Cpu<M> { &self.cpu }
+    pub fn cpu_mut<'a>(&'a mut self) -> &'a mut Cpu<M> { &mut self.cpu }
+
+    //
+    // Breakpoints and watchpoints. Each gets an id, unique across both kinds.
+    //
+
+    fn new_id(&mut self) -> uint {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16, condition: Condition) -> uint {
+        let id = self.new_id();
+        self.breakpoints.push(Breakpoint { id: id, addr: addr, condition: condition, hits: 0 });
+        id
+    }
+
+    /// Watches `lo..hi` (inclusive) for the given kind of access.
+    pub fn add_watchpoint(&mut self, lo: u16, hi: u16, kind: WatchKind) -> uint {
+        let id = self.new_id();
+        self.watchpoints.push(Watchpoint { id: id, lo: lo, hi: hi, kind: kind, hits: 0 });
+        id
+    }
+
+    /// Stops after any instruction that reads or writes the soft switch at `addr`.
+    pub fn add_soft_switch_breakpoint(&mut self, addr: u16) -> uint {
+        assert!(addr >= SOFT_SWITCH_LO && addr <= SOFT_SWITCH_HI);
+        self.add_watchpoint(addr, addr, WatchAccess)
+    }
+
+    /// Removes the breakpoint or watchpoint with the given id, returning false if there's none.
+    pub fn remove(&mut self, id: uint) -> bool {
+        let (nbreak, nwatch) = (self.breakpoints.len(), self.watchpoints.len());
+        self.breakpoints.retain(|bp| bp.id != id);
+        self.watchpoints.retain(|wp| wp.id != id);
+        nbreak != self.breakpoints.len() || nwatch != self.watchpoints.len()
+    }
+
+    pub fn breakpoints<'a>(&'a self) -> &'a [Breakpoint] { self.breakpoints.as_slice() }
+    pub fn watchpoints<'a>(&'a self) -> &'a [Watchpoint] { self.watchpoints.as_slice() }
+
+    /// The hit count of the breakpoint or watchpoint with the given id.
+    pub fn hits(&self, id: uint) -> Option<uint> {
+        for bp in self.breakpoints.iter() {
+            if bp.id == id {
+                return Some(bp.hits);
+            }
+        }
+        for wp in self.watchpoints.iter() {
+            if wp.id == id {
+                return Some(wp.hits);
+            }
+        }
+        None
+    }
+
+    //
+    // Execution
+    //
+
+    // Steps one instruction, or takes an interrupt, and reports whether it halted the CPU or hit
+    // a watchpoint. `ran` is set to whether an instruction ran.
+    fn step_checked(&mut self, ran: &mut bool) -> Option<DebugEvent> {
+        *ran = self.cpu.step();
+        let log = self.cpu.take_bus_log();
+        match self.cpu.halted() {
+            Some(halt) => return Some(CpuHalted(halt)),
+            None => {}
+        }
+        let mut event = None;
+        for access in log.iter() {
+            for wp in self.watchpoints.mut_iter() {
+                if wp.matches(access) {
+                    wp.hits += 1;
+                    if event.is_none() {
+                        event = Some(HitWatchpoint(wp.id, access.clone()));
+                    }
+                }
+            }
+        }
+        event
+    }
+
+    fn check_breakpoints(&mut self) -> Option<DebugEvent> {
+        let pc = self.cpu.pc();
+        let cpu = &mut self.cpu;
+        for bp in self.breakpoints.mut_iter() {
+            if bp.addr == pc && bp.condition.holds(cpu) {
+                bp.hits += 1;
+                return Some(HitBreakpoint(bp.id));
+            }
+        }
+        None
+    }
+
+    // The opcode about to be executed, fetched without going through the bus log, and without
+    // the side effects of a read.
+    fn next_opcode(&mut self) -> u8 {
+        let pc = self.cpu.pc();
+        self.cpu.mem_mut().peekb(pc)
+    }
+
+    // Steps until `done` says the command is finished, given the CPU and the opcode it just
+    // executed, or `None` if it took an interrupt instead. The first instruction runs even if
+    // there's a breakpoint on it, so that a stopped program can be resumed.
+    fn run_while(&mut self, max_cycles: Cycles, done: |&Cpu<M>, Option<u8>| -> bool)
+                 -> DebugEvent {
+        let end = self.cpu.cycles() + max_cycles;
+        loop {
+            match self.rewind {
+                Some(ref mut rewind) => rewind.snapshot(&self.cpu),
+                None => {}
+            }
+            let op = self.next_opcode();
+            let mut ran = false;
+            match self.step_checked(&mut ran) {
+                Some(event) => return event,
+                None => {}
+            }
+            if done(&self.cpu, if ran { Some(op) } else { None }) {
+                return StepDone;
+            }
+            match self.check_breakpoints() {
+                Some(event) => return event,
+                None => {}
+            }
+            if self.cpu.cycles() >= end {
+                return OutOfCycles;
+            }
+        }
+    }
+
+    /// Runs until a breakpoint or watchpoint is hit, or the CPU halts, for at most `max_cycles`.
+    pub fn go(&mut self, max_cycles: Cycles) -> DebugEvent {
+        self.run_while(max_cycles, |_, _| false)
+    }
+
+    /// Executes one instruction.
+    pub fn step_into(&mut self) -> DebugEvent {
+        self.run_while(0, |_, _| true)
+    }
+
+    /// Executes one instruction, or a whole subroutine if it's a JSR: that runs until the PC
+    /// comes back to the following instruction with the stack where it started.
+    pub fn step_over(&mut self, max_cycles: Cycles) -> DebugEvent {
+        if self.next_opcode() != JSR_OPCODE {
+            return self.step_into();
+        }
+        let ret = self.cpu.pc() + 3;
+        let s = self.cpu.s();
+        self.run_while(max_cycles, |cpu, _| cpu.pc() == ret && cpu.s() == s)
+    }
+
+    /// Runs until the current subroutine (or interrupt handler) returns: that is, until an RTS
+    /// or RTI pops the stack above where it is now.
+    pub fn step_out(&mut self, max_cycles: Cycles) -> DebugEvent {
+        let s = self.cpu.s();
+        self.run_while(max_cycles, |cpu, op| {
+            (op == Some(RTS_OPCODE) || op == Some(RTI_OPCODE)) && cpu.s() > s
+        })
+    }
+
+    //
+    // Rewinding
+    //
+
+    // Replays without the replayed accesses reaching the watchpoints.
+    fn rewind_with(&mut self, f: |&mut Rewind<M>, &mut Cpu<M>| -> bool) -> bool {
+        let rewind = match self.rewind {
+            Some(ref mut rewind) => rewind,
+            None => return false
+        };
+        let ok = f(rewind, &mut self.cpu);
+        self.cpu.take_bus_log();
+        ok
+    }
+
+    /// Forgets the rewind history, which no longer leads to the current state if that's been
+    /// replaced, e.g. by loading a save file.
+    pub fn clear_rewind(&mut self) {
+        match self.rewind {
+            Some(ref mut rewind) => rewind.clear(),
+            None => {}
+        }
+    }
+
+    /// Goes back one instruction. Returns false if rewind isn't enabled, or there's no snapshot
+    /// from before it.
+    pub fn step_back(&mut self) -> bool {
+        self.rewind_with(|rewind, cpu| rewind.step_back(cpu))
+    }
+
+    /// Goes back to the last time the PC was `pc`. Returns false if rewind isn't enabled, or it
+    /// wasn't since the oldest snapshot.
+    pub fn run_back_to(&mut self, pc: u16) -> bool {
+        self.rewind_with(|rewind, cpu| rewind.run_back_to(cpu, pc))
+    }
+}
+
+impl<M:Mem+Snapshot> Debugger<M> {
+    /// Snapshots the machine every `interval` cycles while the debugger runs it, keeping the
+    /// last `capacity` snapshots to rewind through.
+    pub fn enable_rewind(&mut self, interval: Cycles, capacity: uint) {
+        self.rewind = Some(Rewind::new(interval, capacity));
+    }
+}
@@ -0,0 +1,230 @@
+//
+// 6502 disassembler
+//
+// This shares the `decode_op!` table with the CPU: every addressing mode method returns the
+// operand text, and every instruction method turns that into the full instruction text. Memory
+// is read with `Mem::peekb`, so disassembling never changes the machine.
+//
+
+use cpu::{CpuVariant, Nmos6502, Cmos65C02};
+use mem::Mem;
+
+pub struct Disassembler<'a, M> {
+    pc: u16,
+    mem: &'a mut M,
+    bytes: ~[u8],
+    variant: CpuVariant,
+}
+
+impl<'a, M:Mem> Disassembler<'a, M> {
+    pub fn new(pc: u16, mem: &'a mut M) -> Disassembler<'a, M> {
+        Disassembler::with_variant(pc, mem, Nmos6502)
+    }
+
+    pub fn with_variant(pc: u16, mem: &'a mut M, variant: CpuVariant) -> Disassembler<'a, M> {
+        Disassembler { pc: pc, mem: mem, bytes: ~[], variant: variant }
+    }
+
+    fn cmos(&self) -> bool { self.variant == Cmos65C02 }
+
+    /// The address of the next instruction to disassemble.
+    pub fn pc(&self) -> u16 { self.pc }
+
+    //
+    // Memory access helpers
+    //
+
+    fn loadb_bump_pc(&mut self) -> u8 {
+        let val = self.mem.peekb(self.pc);
+        self.pc += 1;
+        self.bytes.push(val);
+        val
+    }
+    fn loadw_bump_pc(&mut self) -> u16 {
+        let lo = self.loadb_bump_pc();
+        let hi = self.loadb_bump_pc();
+        lo as u16 | (hi as u16 << 8)
+    }
+
+    //
+    // Mnemonics
+    //
+
+    // Loads
+    fn lda(&mut self, am: ~str) -> ~str { format!("LDA {}", am) }
+    fn ldx(&mut self, am: ~str) -> ~str { format!("LDX {}", am) }
+    fn ldy(&mut self, am: ~str) -> ~str { format!("LDY {}", am) }
+
+    // Stores
+    fn sta(&mut self, am: ~str) -> ~str { format!("STA {}", am) }
+    fn stx(&mut self, am: ~str) -> ~str { format!("STX {}", am) }
+    fn sty(&mut self, am: ~str) -> ~str { format!("STY {}", am) }
+
+    // Arithmetic
+    fn adc(&mut self, am: ~str) -> ~str { format!("ADC {}", am) }
+    fn sbc(&mut self, am: ~str) -> ~str { format!("SBC {}", am) }
+
+    // Comparisons
+    fn cmp(&mut self, am: ~str) -> ~str { format!("CMP {}", am) }
+    fn cpx(&mut self, am: ~str) -> ~str { format!("CPX {}", am) }
+    fn cpy(&mut self, am: ~str) -> ~str { format!("CPY {}", am) }
+
+    // Bitwise operations
+    fn and(&mut self, am: ~str) -> ~str { format!("AND {}", am) }
+    fn ora(&mut self, am: ~str) -> ~str { format!("ORA {}", am) }
+    fn eor(&mut self, am: ~str) -> ~str { format!("EOR {}", am) }
+    fn bit(&mut self, am: ~str) -> ~str { format!("BIT {}", am) }
+
+    // Shifts and rotates
+    fn rol(&mut self, am: ~str) -> ~str { format!("ROL {}", am) }
+    fn ror(&mut self, am: ~str) -> ~str { format!("ROR {}", am) }
+    fn asl(&mut self, am: ~str) -> ~str { format!("ASL {}", am) }
+    fn lsr(&mut self, am: ~str) -> ~str { format!("LSR {}", am) }
+
+    // Increments and decrements
+    fn inc(&mut self, am: ~str) -> ~str { format!("INC {}", am) }
+    fn dec(&mut self, am: ~str) -> ~str { format!("DEC {}", am) }
+    fn inx(&mut self) -> ~str { ~"INX" }
+    fn dex(&mut self) -> ~str { ~"DEX" }
+    fn iny(&mut self) -> ~str { ~"INY" }
+    fn dey(&mut self) -> ~str { ~"DEY" }
+
+    // Register moves
+    fn tax(&mut self) -> ~str { ~"TAX" }
+    fn tay(&mut self) -> ~str { ~"TAY" }
+    fn txa(&mut self) -> ~str { ~"TXA" }
+    fn tya(&mut self) -> ~str { ~"TYA" }
+    fn txs(&mut self) -> ~str { ~"TXS" }
+    fn tsx(&mut self) -> ~str { ~"TSX" }
+
+    // Flag operations
+    fn clc(&mut self) -> ~str { ~"CLC" }
+    fn sec(&mut self) -> ~str { ~"SEC" }
+    fn cli(&mut self) -> ~str { ~"CLI" }
+    fn sei(&mut self) -> ~str { ~"SEI" }
+    fn clv(&mut self) -> ~str { ~"CLV" }
+    fn cld(&mut self) -> ~str { ~"CLD" }
+    fn sed(&mut self) -> ~str { ~"SED" }
+
+    // Branches
+    fn bra_base(&mut self, mnemonic: &str) -> ~str {
+        let disp = self.loadb_bump_pc() as i8;
+        let target = (self.pc as i32 + disp as i32) as u16;
+        format!("{} ${:04X}", mnemonic, target)
+    }
+    fn bpl(&mut self) -> ~str { self.bra_base("BPL") }
+    fn bmi(&mut self) -> ~str { self.bra_base("BMI") }
+    fn bvc(&mut self) -> ~str { self.bra_base("BVC") }
+    fn bvs(&mut self) -> ~str { self.bra_base("BVS") }
+    fn bcc(&mut self) -> ~str { self.bra_base("BCC") }
+    fn bcs(&mut self) -> ~str { self.bra_base("BCS") }
+    fn bne(&mut self) -> ~str { self.bra_base("BNE") }
+    fn beq(&mut self) -> ~str { self.bra_base("BEQ") }
+
+    // Jumps
+    fn jmp(&mut self) -> ~str { format!("JMP ${:04X}", self.loadw_bump_pc()) }
+    fn jmpi(&mut self) -> ~str { format!("JMP (${:04X})", self.loadw_bump_pc()) }
+
+    // Procedure calls
+    fn jsr(&mut self) -> ~str { format!("JSR ${:04X}", self.loadw_bump_pc()) }
+    fn rts(&mut self) -> ~str { ~"RTS" }
+    fn brk(&mut self) -> ~str { ~"BRK" }
+    fn rti(&mut self) -> ~str { ~"RTI" }
+
+    // Stack operations
+    fn pha(&mut self) -> ~str { ~"PHA" }
+    fn pla(&mut self) -> ~str { ~"PLA" }
+    fn php(&mut self) -> ~str { ~"PHP" }
+    fn plp(&mut self) -> ~str { ~"PLP" }
+
+    // No operation
+    fn nop(&mut self) -> ~str { ~"NOP" }
+
+    // 65C02 instructions
+    fn stz(&mut self, am: ~str) -> ~str { format!("STZ {}", am) }
+    fn trb(&mut self, am: ~str) -> ~str { format!("TRB {}", am) }
+    fn tsb(&mut self, am: ~str) -> ~str { format!("TSB {}", am) }
+    fn bit_imm(&mut self, am: ~str) -> ~str { format!("BIT {}", am) }
+    fn bra(&mut self) -> ~str { self.bra_base("BRA") }
+    fn jmpix(&mut self) -> ~str { format!("JMP (${:04X},X)", self.loadw_bump_pc()) }
+    fn phx(&mut self) -> ~str { ~"PHX" }
+    fn phy(&mut self) -> ~str { ~"PHY" }
+    fn plx(&mut self) -> ~str { ~"PLX" }
+    fn ply(&mut self) -> ~str { ~"PLY" }
+    fn nop_read(&mut self, am: ~str) -> ~str { format!("NOP {}", am) }
+
+    // NMOS undocumented instructions
+    fn slo(&mut self, am: ~str) -> ~str { format!("SLO {}", am) }
+    fn rla(&mut self, am: ~str) -> ~str { format!("RLA {}", am) }
+    fn sre(&mut self, am: ~str) -> ~str { format!("SRE {}", am) }
+    fn rra(&mut self, am: ~str) -> ~str { format!("RRA {}", am) }
+    fn sax(&mut self, am: ~str) -> ~str { format!("SAX {}", am) }
+    fn lax(&mut self, am: ~str) -> ~str { format!("LAX {}", am) }
+    fn dcp(&mut self, am: ~str) -> ~str { format!("DCP {}", am) }
+    fn isc(&mut self, am: ~str) -> ~str { format!("ISC {}", am) }
+    fn anc(&mut self, am: ~str) -> ~str { format!("ANC {}", am) }
+    fn alr(&mut self, am: ~str) -> ~str { format!("ALR {}", am) }
+    fn arr(&mut self, am: ~str) -> ~str { format!("ARR {}", am) }
+    fn sbx(&mut self, am: ~str) -> ~str { format!("SBX {}", am) }
+    fn las(&mut self, am: ~str) -> ~str { format!("LAS {}", am) }
+    fn ane(&mut self, _: u8, am: ~str) -> ~str { format!("ANE {}", am) }
+    fn lxa(&mut self, _: u8, am: ~str) -> ~str { format!("LXA {}", am) }
+    fn sha(&mut self, _: u8, am: ~str) -> ~str { format!("SHA {}", am) }
+    fn shx(&mut self, _: u8, am: ~str) -> ~str { format!("SHX {}", am) }
+    fn shy(&mut self, _: u8, am: ~str) -> ~str { format!("SHY {}", am) }
+    fn tas(&mut self, _: u8, am: ~str) -> ~str { format!("TAS {}", am) }
+    fn jam(&mut self, _: u8) -> ~str { ~"JAM" }
+
+    //
+    // Addressing modes
+    //
+
+    fn immediate(&mut self) -> ~str { format!("\\#${:02X}", self.loadb_bump_pc()) }
+    fn accumulator(&mut self) -> ~str { ~"A" }
+    fn zero_page(&mut self) -> ~str { format!("${:02X}", self.loadb_bump_pc()) }
+    fn zero_page_x(&mut self) -> ~str { format!("${:02X},X", self.loadb_bump_pc()) }
+    fn zero_page_y(&mut self) -> ~str { format!("${:02X},Y", self.loadb_bump_pc()) }
+    fn absolute(&mut self) -> ~str { format!("${:04X}", self.loadw_bump_pc()) }
+    fn absolute_x(&mut self) -> ~str { format!("${:04X},X", self.loadw_bump_pc()) }
+    fn absolute_y(&mut self) -> ~str { format!("${:04X},Y", self.loadw_bump_pc()) }
+    fn zero_page_indirect(&mut self) -> ~str { format!("(${:02X})", self.loadb_bump_pc()) }
+    fn indexed_indirect_x(&mut self) -> ~str { format!("(${:02X},X)", self.loadb_bump_pc()) }
+    fn indirect_indexed_y(&mut self) -> ~str { format!("(${:02X}),Y", self.loadb_bump_pc()) }
+
+    /// Disassembles the instruction at the current PC and advances past it, returning just the
+    /// instruction text (e.g. `LDA #$00`).
+    pub fn disassemble(&mut self) -> ~str {
+        self.bytes = ~[];
+        let op = self.loadb_bump_pc();
+        decode_op!(op, self)
+    }
+
+    /// Like `disassemble`, but returns a full listing line with the address and the instruction
+    /// bytes (e.g. `0300  A9 00     LDA #$00`).
+    pub fn disassemble_line(&mut self) -> ~str {
+        let pc = self.pc;
+        let text = self.disassemble();
+        let bytes: ~[~str] = self.bytes.iter().map(|b| format!("{:02X}", *b)).collect();
+        format!("{:04X}  {:<8s}  {}", pc, bytes.connect(" "), text)
+    }
+}
+
+/// Disassembles every instruction that starts between `start` and `end` (inclusive), one listing
+/// line per instruction, or nothing if `end` is before `start`. Nothing is executed, and the
+/// bytes are fetched with `Mem::peekb`, so disassembling I/O space leaves its soft switches alone.
+pub fn disassemble_range<M:Mem>(mem: &mut M, start: u16, end: u16, variant: CpuVariant)
+                                -> ~[~str] {
+    let mut lines = ~[];
+    if end < start {
+        return lines;
+    }
+    let mut disassembler = Disassembler::with_variant(start, mem, variant);
+    let len = (end - start) as uint + 1;
+    let mut offset = 0;
+    while offset < len {
+        let pc = disassembler.pc;
+        lines.push(disassembler.disassemble_line());
+        offset += (disassembler.pc - pc) as uint;
+    }
+    lines
+}
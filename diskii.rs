@@ -1,6 +1,7 @@
 
 use a2::Peripheral;
 use std::io::File;
+use std::vec::bytes::copy_memory;
 
 static NUM_DRIVES: uint = 2;
 static NUM_TRACKS: uint = 35;
@@ -30,6 +31,10 @@ pub struct DiskController
    motor: bool,		// is motor on?
    read_mode: bool,
    write_protect: bool,
+   prom: [u8, ..256],	// the boot code at $Cn00
+   prom_name: ~str,
+   sequencer: ~[u8],	// the P6 PROM, if there's a dump of it; the sequencer isn't emulated from it yet
+   sequencer_name: ~str,
 }
 
 impl DiskController
@@ -40,8 +45,28 @@ impl DiskController
       motor: false,
       read_mode: false,
       write_protect: false,
+      prom: PROM,
+      prom_name: ~"built-in boot PROM",
+      sequencer: ~[],
+      sequencer_name: ~"",
    } }
    
+   // replaces the built-in boot code, which skips a delay so that disks boot faster, with a
+   // dump of the real thing
+   pub fn load_boot_rom(&mut self, rom: &[u8], name: ~str)
+   {
+      assert!(rom.len() == self.prom.len());
+      copy_memory(self.prom, rom);
+      self.prom_name = name;
+   }
+   
+   pub fn load_sequencer_rom(&mut self, rom: &[u8], name: ~str)
+   {
+      assert!(rom.len() == 0x100);
+      self.sequencer = rom.to_owned();
+      self.sequencer_name = name;
+   }
+   
    pub fn load_disk(&mut self, disknum: int, imagefilename: &str)
    {
       let mut f = File::open(&Path::new(imagefilename));
@@ -125,7 +150,7 @@ impl Peripheral for DiskController
  */
    fn doHighIO(&mut self, addr: u16, val: u8) -> u8
    {
-      PROM[addr & 0xff]
+      self.prom[addr & 0xff]
    }
 
    fn doIO(&mut self, addr: u16, val: u8) -> u8
@@ -184,7 +209,87 @@ impl Peripheral for DiskController
       return val;
    }
 
+   fn status(&self) -> ~str
+   {
+      let motor = if self.motor { "on" } else { "off" };
+      let disk = match self.drives[self.selected]
+      {
+         Some(ref drive) => format!("track {}{}", drive.half_track >> 1,
+                                    if (drive.half_track & 1) != 0 { ".5" } else { "" }),
+         None => ~"no disk"
+      };
+      format!("disk drive {}, motor {}, {}", self.selected + 1, motor, disk)
+   }
+
+   fn rom_names(&self) -> ~[~str]
+   {
+      let mut names = ~[self.prom_name.clone()];
+      if (self.sequencer.len() > 0) { names.push(self.sequencer_name.clone()); }
+      names
+   }
 
+   // the disk images aren't saved, just where the heads are and the track under each
+   fn save_state(&self, w: &mut Writer)
+   {
+      w.write([self.selected, self.motor as u8, self.read_mode as u8, self.write_protect as u8]);
+      for drive in self.drives.iter()
+      {
+         match *drive {
+            Some(ref d) => {
+               w.write_u8(1);
+               w.write_le_u16(d.half_track as u16);
+               w.write_le_u16(d.track_index as u16);
+               w.write(d.track_data.as_slice());
+            }
+            None => w.write_u8(0)
+         }
+      }
+   }
+
+   fn check_state(&self, data: &[u8]) -> Result<(), ~str>
+   {
+      let mut pos = 4;
+      for drive in self.drives.iter()
+      {
+         if (data.len() <= pos) {
+            return Err(~"the snapshot's disk controller is truncated");
+         }
+         let present = data[pos] != 0;
+         pos += 1;
+         match *drive {
+            Some(_) if present => pos += 4 + RAW_TRACK_SIZE,
+            None if !present => (),
+            _ => return Err(~"the snapshot's disks don't match this controller's")
+         }
+      }
+      if (data.len() < pos) {
+         return Err(~"the snapshot's disk controller is truncated");
+      }
+      Ok(())
+   }
+
+   fn load_state(&mut self, r: &mut Reader)
+   {
+      let b = r.read_bytes(4);
+      self.selected = b[0];
+      self.motor = b[1] != 0;
+      self.read_mode = b[2] != 0;
+      self.write_protect = b[3] != 0;
+      for drive in self.drives.mut_iter()
+      {
+         let present = r.read_u8() != 0;
+         match *drive {
+            Some(ref mut d) if present => {
+               d.half_track = r.read_le_u16() as uint;
+               d.track_index = r.read_le_u16() as uint;
+               let track = r.read_bytes(RAW_TRACK_SIZE);
+               copy_memory(d.track_data.mut_slice_from(0), track);
+            }
+            None if !present => (),
+            _ => fail!("the snapshot's disks don't match this controller's")
+         }
+      }
+   }
 }
 
 /* --------------- TRACK CONVERSION ROUTINES ---------------------- */
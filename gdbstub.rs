@@ -0,0 +1,359 @@
+//
+// GDB remote serial protocol stub
+//
+// Serves one GDB connection over any stream, driving a `Debugger`. There's no standard GDB
+// register layout for the 6502, so this uses six registers: A, X, Y, S and P, a byte each, then
+// PC, two bytes little-endian. Breakpoints (Z0, Z1) become debugger breakpoints and watchpoints
+// (Z2-Z4) debugger watchpoints. Memory is read with `Mem::peekb`, so GDB looking at I/O space
+// doesn't change the machine, and written with `Mem::storeb`, just as the CPU would write it.
+//
+// The stream is read blocking, so GDB can't interrupt a continue. Instead a continue runs for at
+// most `continue_cycles` and then stops with SIGINT, as if it had been interrupted.
+//
+
+use std::io::{Reader, Writer};
+use std::num::from_str_radix;
+use std::str;
+use cpu::Cycles;
+use debugger::{Debugger, DebugEvent, Always, WatchKind, WatchRead, WatchWrite, WatchAccess};
+use debugger::{StepDone, HitBreakpoint, HitWatchpoint, CpuHalted, OutOfCycles};
+use mem::Mem;
+
+static SIGINT: u8 = 2;
+static SIGILL: u8 = 4;
+static SIGTRAP: u8 = 5;
+
+static NUM_REGS: uint = 6;
+
+// The longest packet sent or received, as advertised in qSupported
+static PACKET_SIZE: uint = 1000;
+// The most an m packet reads, so that the reply fits a packet with its $, # and checksum. GDB
+// asks again for the rest of a short read.
+static MAX_READ: u16 = ((PACKET_SIZE - 4) / 2) as u16;
+
+// A breakpoint or watchpoint GDB has set: the Z packet type, address and length, and the id the
+// debugger gave it.
+struct Point {
+    ztype: char,
+    addr: u16,
+    len: u16,
+    id: uint,
+}
+
+pub struct GdbStub {
+    /// The longest a continue runs before it's reported as interrupted.
+    continue_cycles: Cycles,
+    points: ~[Point],
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    if s.len() == 0 || s.len() > 4 {
+        return None;
+    }
+    from_str_radix::<u16>(s, 16)
+}
+
+fn hex_bytes(bytes: &[u8]) -> ~str {
+    let digits: ~[~str] = bytes.iter().map(|b| format!("{:02x}", *b)).collect();
+    digits.concat()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<~[u8]> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let mut bytes = ~[];
+    for i in range(0, s.len() / 2) {
+        match from_str_radix::<u8>(s.slice(i * 2, i * 2 + 2), 16) {
+            Some(b) => bytes.push(b),
+            None => return None
+        }
+    }
+    Some(bytes)
+}
+
+// "addr,len", as in the m, M and Z packets.
+fn parse_addr_len(s: &str) -> Option<(u16, u16)> {
+    let parts: ~[&str] = s.split(',').collect();
+    if parts.len() != 2 {
+        return None;
+    }
+    match (parse_hex(parts[0]), parse_hex(parts[1])) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None
+    }
+}
+
+// Reads the next packet and acknowledges it, returning its payload, or None at the end of the
+// stream. Anything between packets, like GDB's own acks, is skipped.
+fn read_packet<S: Reader+Writer>(stream: &mut S) -> Option<~str> {
+    loop {
+        loop {
+            match stream.read_byte() {
+                Some(b) if b == '$' as u8 => break,
+                Some(_) => {}
+                None => return None
+            }
+        }
+        let mut data = ~[];
+        loop {
+            match stream.read_byte() {
+                Some(b) if b == '#' as u8 => break,
+                Some(b) => data.push(b),
+                None => return None
+            }
+        }
+        let checksum = match (stream.read_byte(), stream.read_byte()) {
+            (Some(hi), Some(lo)) => {
+                match ((hi as char).to_digit(16), (lo as char).to_digit(16)) {
+                    (Some(hi), Some(lo)) => Some((hi * 16 + lo) as u8),
+                    _ => None
+                }
+            }
+            _ => return None
+        };
+        let sum = data.iter().fold(0u8, |sum, b| sum + *b);
+        if checksum == Some(sum) {
+            match str::from_utf8_owned_opt(data) {
+                Some(packet) => {
+                    stream.write(bytes!("+"));
+                    return Some(packet);
+                }
+                None => {}
+            }
+        }
+        stream.write(bytes!("-"));
+    }
+}
+
+fn write_packet<S: Writer>(stream: &mut S, data: &str) {
+    let sum = data.bytes().fold(0u8, |sum, b| sum + b);
+    stream.write(format!("${}\\#{:02x}", data, sum).as_bytes());
+    stream.flush();
+}
+
+fn read_register<M:Mem>(dbg: &Debugger<M>, n: uint) -> ~[u8] {
+    let cpu = dbg.cpu();
+    match n {
+        0 => ~[cpu.a()],
+        1 => ~[cpu.x()],
+        2 => ~[cpu.y()],
+        3 => ~[cpu.s()],
+        4 => ~[cpu.p()],
+        _ => ~[cpu.pc() as u8, (cpu.pc() >> 8) as u8],
+    }
+}
+
+// Returns false if the value is the wrong size for the register.
+fn write_register<M:Mem>(dbg: &mut Debugger<M>, n: uint, val: &[u8]) -> bool {
+    let cpu = dbg.cpu_mut();
+    match (n, val.len()) {
+        (0, 1) => cpu.set_a(val[0]),
+        (1, 1) => cpu.set_x(val[0]),
+        (2, 1) => cpu.set_y(val[0]),
+        (3, 1) => cpu.set_s(val[0]),
+        (4, 1) => cpu.set_p(val[0]),
+        (5, 2) => cpu.set_pc(val[0] as u16 | (val[1] as u16 << 8)),
+        _ => return false
+    }
+    true
+}
+
+impl GdbStub {
+    pub fn new(continue_cycles: Cycles) -> GdbStub {
+        GdbStub { continue_cycles: continue_cycles, points: ~[] }
+    }
+
+    /// Serves GDB until it detaches or kills the target, or the stream ends. Breakpoints and
+    /// watchpoints it set are removed from the debugger when it goes.
+    pub fn serve<M:Mem, S: Reader+Writer>(&mut self, dbg: &mut Debugger<M>, stream: &mut S) {
+        loop {
+            let packet = match read_packet(stream) {
+                Some(packet) => packet,
+                None => break
+            };
+            let (reply, more) = self.handle(dbg, packet);
+            for reply in reply.iter() {
+                write_packet(stream, *reply);
+            }
+            if !more {
+                break;
+            }
+        }
+        for point in self.points.iter() {
+            dbg.remove(point.id);
+        }
+        self.points = ~[];
+    }
+
+    fn stop_reply(&self, event: DebugEvent) -> ~str {
+        match event {
+            StepDone | HitBreakpoint(_) => format!("S{:02x}", SIGTRAP),
+            HitWatchpoint(id, access) => {
+                let name = match self.points.iter().find(|p| p.id == id).map(|p| p.ztype) {
+                    Some('2') => "watch",
+                    Some('3') => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, access.addr)
+            }
+            CpuHalted(_) => format!("S{:02x}", SIGILL),
+            OutOfCycles => format!("S{:02x}", SIGINT),
+        }
+    }
+
+    // The c and s packets can give an address to resume from.
+    fn resume_at<M:Mem>(dbg: &mut Debugger<M>, args: &str) -> bool {
+        if args.len() == 0 {
+            return true;
+        }
+        match parse_hex(args) {
+            Some(pc) => { dbg.cpu_mut().set_pc(pc); true }
+            None => false
+        }
+    }
+
+    // Z and z: "type,addr,kind", where kind is the length for watchpoints.
+    fn set_point<M:Mem>(&mut self, dbg: &mut Debugger<M>, args: &str, insert: bool) -> ~str {
+        if args.len() < 2 || args[1] != ',' as u8 {
+            return ~"E01";
+        }
+        let ztype = args.char_at(0);
+        let (addr, len) = match parse_addr_len(args.slice_from(2)) {
+            Some(point) => point,
+            None => return ~"E01"
+        };
+        if !insert {
+            let found = self.points.iter().position(|p| {
+                p.ztype == ztype && p.addr == addr && p.len == len
+            });
+            return match found {
+                Some(i) => {
+                    dbg.remove(self.points[i].id);
+                    self.points.remove(i);
+                    ~"OK"
+                }
+                None => ~"E01"
+            };
+        }
+        let kind: Option<WatchKind> = match ztype {
+            '0' | '1' => None,
+            '2' => Some(WatchWrite),
+            '3' => Some(WatchRead),
+            '4' => Some(WatchAccess),
+            _ => return ~""
+        };
+        let id = match kind {
+            None => dbg.add_breakpoint(addr, Always),
+            Some(kind) => {
+                if len == 0 {
+                    return ~"E01";
+                }
+                dbg.add_watchpoint(addr, addr + (len - 1), kind)
+            }
+        };
+        self.points.push(Point { ztype: ztype, addr: addr, len: len, id: id });
+        ~"OK"
+    }
+
+    // Handles one packet, returning the reply, if any, and whether to carry on serving.
+    fn handle<M:Mem>(&mut self, dbg: &mut Debugger<M>, packet: &str) -> (Option<~str>, bool) {
+        if packet.len() == 0 {
+            return (Some(~""), true);
+        }
+        let args = packet.slice_from(1);
+        let reply = match packet.char_at(0) {
+            '?' => format!("S{:02x}", SIGTRAP),
+            'g' => {
+                let regs: ~[~[u8]] = range(0, NUM_REGS).map(|n| read_register(dbg, n)).collect();
+                hex_bytes(regs.concat_vec())
+            }
+            'G' => {
+                match parse_hex_bytes(args) {
+                    Some(bytes) if bytes.len() == NUM_REGS + 1 => {
+                        for n in range(0, NUM_REGS - 1) {
+                            write_register(dbg, n, bytes.slice(n, n + 1));
+                        }
+                        write_register(dbg, NUM_REGS - 1, bytes.slice_from(NUM_REGS - 1));
+                        ~"OK"
+                    }
+                    _ => ~"E01"
+                }
+            }
+            'p' => {
+                match parse_hex(args) {
+                    Some(n) if (n as uint) < NUM_REGS => hex_bytes(read_register(dbg, n as uint)),
+                    _ => ~"E01"
+                }
+            }
+            'P' => {
+                let parts: ~[&str] = args.split('=').collect();
+                let parsed = if parts.len() == 2 {
+                    (parse_hex(parts[0]), parse_hex_bytes(parts[1]))
+                } else {
+                    (None, None)
+                };
+                let ok = match parsed {
+                    (Some(n), Some(val)) => write_register(dbg, n as uint, val),
+                    _ => false
+                };
+                if ok { ~"OK" } else { ~"E01" }
+            }
+            'm' => {
+                match parse_addr_len(args) {
+                    Some((addr, len)) => {
+                        let len = if len > MAX_READ { MAX_READ } else { len };
+                        let bytes: ~[u8] = range(0, len).map(|i| {
+                            dbg.cpu_mut().mem_mut().peekb(addr + i)
+                        }).collect();
+                        hex_bytes(bytes)
+                    }
+                    None => ~"E01"
+                }
+            }
+            'M' => {
+                let parts: ~[&str] = args.split(':').collect();
+                let parsed = if parts.len() == 2 {
+                    (parse_addr_len(parts[0]), parse_hex_bytes(parts[1]))
+                } else {
+                    (None, None)
+                };
+                match parsed {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len as uint => {
+                        for (i, b) in bytes.iter().enumerate() {
+                            dbg.cpu_mut().mem_mut().storeb(addr + i as u16, *b);
+                        }
+                        ~"OK"
+                    }
+                    _ => ~"E01"
+                }
+            }
+            'Z' => self.set_point(dbg, args, true),
+            'z' => self.set_point(dbg, args, false),
+            's' => {
+                if !GdbStub::resume_at(dbg, args) {
+                    ~"E01"
+                } else {
+                    let event = dbg.step_into();
+                    self.stop_reply(event)
+                }
+            }
+            'c' => {
+                if !GdbStub::resume_at(dbg, args) {
+                    ~"E01"
+                } else {
+                    let event = dbg.go(self.continue_cycles);
+                    self.stop_reply(event)
+                }
+            }
+            'H' => ~"OK",
+            'q' if args.starts_with("Supported") => format!("PacketSize={}", PACKET_SIZE),
+            'q' if args == "Attached" => ~"1",
+            'D' => return (Some(~"OK"), false),
+            'k' => return (None, false),
+            // Anything else is unsupported, which GDB expects an empty reply for
+            _ => ~""
+        };
+        (Some(reply), true)
+    }
+}
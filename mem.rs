@@ -12,6 +12,38 @@
 pub trait Mem {
     fn loadb(&mut self, addr: u16) -> u8;
     fn storeb(&mut self, addr: u16, val: u8);
+
+    /// A CPU in cycle-accurate mode calls these instead, passing the cycle the access happens on.
+    fn loadb_cy(&mut self, addr: u16, _: u64) -> u8 { self.loadb(addr) }
+    fn storeb_cy(&mut self, addr: u16, val: u8, _: u64) { self.storeb(addr, val) }
+
+    /// Reads a byte the way a debugger looks at memory, without the side effects a read by the
+    /// CPU would have, such as flipping a soft switch. Memory where reads have none can leave
+    /// this as `loadb`.
+    fn peekb(&mut self, addr: u16) -> u8 { self.loadb(addr) }
+
+    /// The level of the IRQ line, polled by the CPU between instructions.
+    fn irq_line(&mut self) -> bool { false }
+    /// The level of the NMI line. The CPU takes an NMI when this goes from low to high.
+    fn nmi_line(&mut self) -> bool { false }
+}
+
+/// A machine-specific hook on the CPU's bus, registered with `Cpu::add_hook` for a range of
+/// addresses. Hooks see the CPU's accesses to their range before memory does, so a system can
+/// intercept addresses without patching the CPU core.
+pub trait BusHook {
+    /// Returns the value to supply for a read, or `None` to let the read through to memory.
+    fn loadb(&mut self, addr: u16) -> Option<u8>;
+    /// Returns true if the write was handled and should not reach memory.
+    fn storeb(&mut self, addr: u16, val: u8) -> bool;
+}
+
+/// One byte read or written by the CPU, as recorded by `Cpu::set_bus_log`.
+#[deriving(Eq, Clone)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub val: u8,
+    pub write: bool,
 }
 
 pub trait MemUtil {
@@ -0,0 +1,319 @@
+//
+// Monitor-style debugger console
+//
+// Parses and runs commands in the style of the Apple II Monitor: an optional hex address, then a
+// command. Commands are letters that can't be hex digits, so `300L` is unambiguous.
+//
+//   300         examine a byte           300.3FF     examine a range
+//   300:A9 00   deposit bytes            300L, L     disassemble (20 instructions)
+//   300G, G     go                       R           registers
+//   S           step                     N           step over a JSR
+//   O           step out                 I           soft switch state
+//   300P, P     set or list breakpoints  300K, K     clear one or all breakpoints
+//   T           trace on or off          300.3FFT    trace only that range
+//   V           view the trace           U           step back
+//   300U        run back to 300          H           help
+//   SAVE file   save the machine state   LOAD file   load it
+//   Q           quit
+//
+// While a movie is being recorded, the commands that change the machine other than by running
+// it forward (deposits, going to an address, stepping back and loading) are refused, since the
+// movie couldn't play them back. Examining and listing memory read it with `Mem::peekb`, so they
+// never change the machine, soft switches included.
+//
+
+use std::ascii::StrAsciiExt;
+use std::io;
+use std::io::File;
+use std::num::from_str_radix;
+use a2::AppleII;
+use cpu::Cycles;
+use debugger::{Debugger, DebugEvent, Always};
+use debugger::{StepDone, HitBreakpoint, HitWatchpoint, CpuHalted, OutOfCycles};
+use disasm::Disassembler;
+use mem::Mem;
+use trace::{Tracer, TraceFilter};
+use state::{write_save_file, read_save_file};
+
+static LIST_LINES: uint = 20;
+static TRACE_RECORDS: uint = 1000;
+static VIEW_RECORDS: uint = 20;
+
+// How long stepping over or out of a subroutine may run before the monitor gives up on it.
+static STEP_CYCLES: Cycles = 10 * 1000000;
+
+static HELP: [&'static str, ..7] = [
+    "300 300.3FF 300:A9 00  EXAMINE/DEPOSIT",
+    "300L L  LIST     300G G  GO      R  REGS",
+    "S  STEP   N  NEXT   O  OUT    I  SWITCHES",
+    "300P P  BREAKPOINTS   300K K  CLEAR   Q  QUIT",
+    "T  TRACE ON/OFF   300.3FFT  TRACE RANGE   V  VIEW",
+    "U  STEP BACK   300U  RUN BACK TO 300",
+    "SAVE FILE  LOAD FILE  MACHINE STATE",
+];
+
+/// What the frontend should do after a command.
+pub enum MonitorReply {
+    /// Print these lines and wait for another command.
+    Print(~[~str]),
+    /// Resume emulation.
+    Go,
+    /// Leave the emulator.
+    Quit,
+}
+
+pub struct Monitor {
+    next_list: Option<u16>,     // where a bare L carries on from
+    recording: bool,
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    if s.len() == 0 || s.len() > 4 {
+        return None;
+    }
+    from_str_radix::<u16>(s, 16)
+}
+
+/// Describes why a debugger command stopped, for the console.
+pub fn describe_event(event: &DebugEvent) -> ~str {
+    match *event {
+        StepDone => ~"",
+        HitBreakpoint(id) => format!("BREAKPOINT {}", id),
+        HitWatchpoint(id, ref access) => {
+            format!("WATCHPOINT {}: {} {:04X} = {:02X}", id,
+                    if access.write { "WRITE" } else { "READ" }, access.addr, access.val)
+        }
+        CpuHalted(halt) => format!("HALTED: {:?}", halt),
+        OutOfCycles => ~"STILL RUNNING",
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor { Monitor { next_list: None, recording: false } }
+
+    /// Says whether a movie is being recorded.
+    pub fn set_recording(&mut self, recording: bool) { self.recording = recording; }
+
+    fn registers(dbg: &Debugger<AppleII>) -> ~str {
+        let cpu = dbg.cpu();
+        format!("A={:02X} X={:02X} Y={:02X} P={:02X} S={:02X} PC={:04X}",
+                cpu.a(), cpu.x(), cpu.y(), cpu.p(), cpu.s(), cpu.pc())
+    }
+
+    fn examine(dbg: &mut Debugger<AppleII>, start: u16, end: u16) -> ~[~str] {
+        let mut lines = ~[];
+        let mut line = format!("{:04X}-", start);
+        let mut addr = start as uint;
+        while addr <= end as uint {
+            if addr != start as uint && (addr & 7) == 0 {
+                lines.push(line);
+                line = format!("{:04X}-", addr);
+            }
+            line.push_str(format!(" {:02X}", dbg.cpu_mut().mem_mut().peekb(addr as u16)));
+            addr += 1;
+        }
+        lines.push(line);
+        lines
+    }
+
+    fn list(&mut self, dbg: &mut Debugger<AppleII>, start: u16) -> ~[~str] {
+        let variant = dbg.cpu().variant();
+        let mut disassembler =
+            Disassembler::with_variant(start, dbg.cpu_mut().mem_mut(), variant);
+        let lines: ~[~str] =
+            range(0, LIST_LINES).map(|_| disassembler.disassemble_line()).collect();
+        self.next_list = Some(disassembler.pc());
+        lines
+    }
+
+    // Turns tracing on, for PCs in the range.
+    fn trace_range(dbg: &mut Debugger<AppleII>, lo: u16, hi: u16) -> ~[~str] {
+        let mut tracer = Tracer::new(TRACE_RECORDS);
+        tracer.set_filter(TraceFilter { pc_lo: lo, pc_hi: hi, soft_switches: false });
+        dbg.cpu_mut().set_tracer(Some(tracer));
+        ~[format!("TRACING {:04X}-{:04X}", lo, hi)]
+    }
+
+    fn view_trace(dbg: &mut Debugger<AppleII>) -> ~[~str] {
+        let variant = dbg.cpu().variant();
+        let records = match dbg.cpu_mut().tracer() {
+            Some(tracer) => tracer.records(),
+            None => return ~[~"NOT TRACING"]
+        };
+        let start = records.len() - records.len().min(&VIEW_RECORDS);
+        records.slice_from(start).iter().map(|record| record.to_text(variant)).collect()
+    }
+
+    fn save(dbg: &mut Debugger<AppleII>, path: &str) -> ~[~str] {
+        let file = io::io_error::cond.trap(|_| {}).inside(|| File::create(&Path::new(path)));
+        match file {
+            Some(mut file) => {
+                write_save_file(&mut file, dbg.cpu().mem().model_name(), dbg.cpu());
+                ~[format!("SAVED {}", path)]
+            }
+            None => ~[format!("CAN'T WRITE {}", path)]
+        }
+    }
+
+    fn load(dbg: &mut Debugger<AppleII>, path: &str) -> ~[~str] {
+        let file = io::io_error::cond.trap(|_| {}).inside(|| File::open(&Path::new(path)));
+        let mut file = match file {
+            Some(file) => file,
+            None => return ~[format!("CAN'T READ {}", path)]
+        };
+        let model = dbg.cpu().mem().model_name();
+        match read_save_file(&mut file, model, dbg.cpu_mut()) {
+            Ok(()) => {
+                dbg.clear_rewind();
+                ~[format!("LOADED {}", path)]
+            }
+            Err(e) => ~[e.to_ascii_upper()]
+        }
+    }
+
+    // After a step: why it stopped, if it wasn't just the step finishing, then the registers and
+    // the next instruction.
+    fn stepped(&mut self, dbg: &mut Debugger<AppleII>, event: DebugEvent) -> ~[~str] {
+        let mut lines = ~[];
+        if event != StepDone {
+            lines.push(describe_event(&event));
+        }
+        lines.push(Monitor::registers(dbg));
+        let pc = dbg.cpu().pc();
+        let variant = dbg.cpu().variant();
+        lines.push(Disassembler::with_variant(pc, dbg.cpu_mut().mem_mut(), variant)
+                       .disassemble_line());
+        lines
+    }
+
+    /// Runs one command line.
+    pub fn execute(&mut self, dbg: &mut Debugger<AppleII>, line: &str) -> MonitorReply {
+        // SAVE and LOAD take a file name, so they're picked out before upper-casing
+        let words: ~[&str] = line.trim().splitn(' ', 1).collect();
+        if words.len() == 2 {
+            match words[0].to_ascii_upper().as_slice() {
+                "SAVE" => return Print(Monitor::save(dbg, words[1].trim())),
+                "LOAD" if self.recording => return Print(~[~"NOT WHILE RECORDING"]),
+                "LOAD" => return Print(Monitor::load(dbg, words[1].trim())),
+                _ => {}
+            }
+        }
+
+        let line = line.trim().to_ascii_upper();
+        let line = line.as_slice();
+
+        // The leading address, if any
+        let split = line.find(|c: char| !c.is_digit_radix(16)).unwrap_or(line.len());
+        let addr = parse_hex(line.slice_to(split));
+        let rest = line.slice_from(split).trim_left();
+        if split > 0 && addr.is_none() {
+            return Print(~[~"BAD ADDRESS"]);
+        }
+
+        if rest.starts_with(".") {
+            let trace = rest.ends_with("T");
+            let end = rest.slice(1, if trace { rest.len() - 1 } else { rest.len() });
+            return match (addr, parse_hex(end.trim())) {
+                (Some(start), Some(end)) if start <= end => {
+                    if trace {
+                        Print(Monitor::trace_range(dbg, start, end))
+                    } else {
+                        Print(Monitor::examine(dbg, start, end))
+                    }
+                }
+                _ => Print(~[~"BAD RANGE"])
+            };
+        }
+        let changes_machine =
+            rest.starts_with(":") || rest == "U" || (addr.is_some() && rest == "G");
+        if self.recording && changes_machine {
+            return Print(~[~"NOT WHILE RECORDING"]);
+        }
+        if rest.starts_with(":") {
+            let start = match addr {
+                Some(start) => start,
+                None => return Print(~[~"BAD ADDRESS"])
+            };
+            let bytes: ~[Option<u16>] =
+                rest.slice_from(1).words().map(|w| parse_hex(w)).collect();
+            if bytes.iter().any(|b| b.is_none() || b.unwrap() > 0xff) {
+                return Print(~[~"BAD BYTE"]);
+            }
+            for (i, b) in bytes.iter().enumerate() {
+                dbg.cpu_mut().mem_mut().storeb(start + i as u16, b.unwrap() as u8);
+            }
+            return Print(~[]);
+        }
+
+        match (addr, rest) {
+            (None, "") => Print(~[]),
+            (Some(addr), "") => Print(Monitor::examine(dbg, addr, addr)),
+            (_, "L") => {
+                let start = addr.or(self.next_list).unwrap_or(dbg.cpu().pc());
+                Print(self.list(dbg, start))
+            }
+            (_, "G") => {
+                for &pc in addr.iter() {
+                    dbg.cpu_mut().set_pc(pc);
+                }
+                Go
+            }
+            (None, "R") => Print(~[Monitor::registers(dbg)]),
+            (None, "S") => {
+                let event = dbg.step_into();
+                Print(self.stepped(dbg, event))
+            }
+            (None, "N") => {
+                let event = dbg.step_over(STEP_CYCLES);
+                Print(self.stepped(dbg, event))
+            }
+            (None, "O") => {
+                let event = dbg.step_out(STEP_CYCLES);
+                Print(self.stepped(dbg, event))
+            }
+            (None, "I") => Print(dbg.cpu().mem().status()),
+            (Some(addr), "P") => {
+                let id = dbg.add_breakpoint(addr, Always);
+                Print(~[format!("BREAKPOINT {} AT {:04X}", id, addr)])
+            }
+            (None, "P") => {
+                Print(dbg.breakpoints().iter().map(|bp| {
+                    format!("{}: {:04X} HITS {}", bp.id, bp.addr, bp.hits)
+                }).collect())
+            }
+            (_, "K") => {
+                let ids: ~[uint] = dbg.breakpoints().iter()
+                                      .filter(|bp| addr.is_none() || addr == Some(bp.addr))
+                                      .map(|bp| bp.id).collect();
+                for &id in ids.iter() {
+                    dbg.remove(id);
+                }
+                Print(~[])
+            }
+            (None, "U") => {
+                if dbg.step_back() {
+                    Print(self.stepped(dbg, StepDone))
+                } else {
+                    Print(~[~"CAN'T GO BACK"])
+                }
+            }
+            (Some(addr), "U") => {
+                if dbg.run_back_to(addr) {
+                    Print(self.stepped(dbg, StepDone))
+                } else {
+                    Print(~[format!("NOT AT {:04X} SINCE THE OLDEST SNAPSHOT", addr)])
+                }
+            }
+            (None, "T") => {
+                match dbg.cpu_mut().set_tracer(None) {
+                    Some(_) => Print(~[~"TRACE OFF"]),
+                    None => Print(Monitor::trace_range(dbg, 0, 0xffff))
+                }
+            }
+            (None, "V") => Print(Monitor::view_trace(dbg)),
+            (None, "H") => Print(HELP.iter().map(|s| s.to_owned()).collect()),
+            (None, "Q") => Quit,
+            _ => Print(~[~"SYNTAX ERROR"])
+        }
+    }
+}
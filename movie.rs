@@ -0,0 +1,186 @@
+//
+// Input movies
+//
+// A movie is a snapshot to start from, and the input fed to the machine after it, each event
+// stamped with the cycle it arrived on. Input only reaches the machine between instructions, and
+// the machine is deterministic given its input, so playing a movie back from its snapshot gives
+// the same run, cycle for cycle.
+//
+// A movie file is `MOVIE_MAGIC`, the format version as a 16-bit little-endian number, the machine
+// model as a length-prefixed string, the snapshot's length as a 32-bit number and the snapshot,
+// then the number of events as a 32-bit number and the events. Each event is its cycle as a
+// 64-bit number, a kind byte and a value byte. Numbers are little-endian.
+//
+
+use std::io::{Reader, Writer};
+use std::str;
+use a2::AppleII;
+use cpu::{Cpu, Cycles};
+use state::{save_to_vec, load_from_slice};
+
+pub static MOVIE_MAGIC: &'static [u8] = bytes!("A2MOVIE", 0x1a);
+pub static MOVIE_VERSION: u16 = 1;
+
+static KEY_PRESS_KIND: u8 = 1;
+
+/// Input from outside the machine.
+#[deriving(Eq, Clone)]
+pub enum InputEvent {
+    /// A key, as passed to `AppleII::keyPressed`.
+    KeyPress(u8),
+}
+
+impl InputEvent {
+    pub fn apply(&self, a2: &mut AppleII) {
+        match *self {
+            KeyPress(key) => a2.keyPressed(key),
+        }
+    }
+}
+
+pub struct Movie {
+    /// The snapshot the movie starts from.
+    pub start: ~[u8],
+    /// The input, in order, with the cycle each arrived on.
+    pub events: ~[(Cycles, InputEvent)],
+}
+
+impl Movie {
+    /// Starts recording a movie from the machine's current state.
+    pub fn record(cpu: &Cpu<AppleII>) -> Movie {
+        Movie { start: save_to_vec(cpu), events: ~[] }
+    }
+
+    /// Feeds input to the machine, and records it.
+    pub fn input(&mut self, cpu: &mut Cpu<AppleII>, event: InputEvent) {
+        event.apply(cpu.mem_mut());
+        self.events.push((cpu.cycles(), event));
+    }
+
+    pub fn write_to(&self, w: &mut Writer, model: &str) {
+        w.write(MOVIE_MAGIC);
+        w.write_le_u16(MOVIE_VERSION);
+        w.write_u8(model.len() as u8);
+        w.write(model.as_bytes());
+        w.write_le_u32(self.start.len() as u32);
+        w.write(self.start);
+        w.write_le_u32(self.events.len() as u32);
+        for &(cy, event) in self.events.iter() {
+            w.write_le_u64(cy);
+            match event {
+                KeyPress(key) => w.write([KEY_PRESS_KIND, key]),
+            }
+        }
+    }
+
+    /// Reads a movie for a machine of the given model. An error means the file isn't a movie
+    /// for it, or is damaged.
+    pub fn read_from(r: &mut Reader, model: &str) -> Result<Movie, ~str> {
+        let data = r.read_to_end();
+        let mut r = MovieReader { data: data.as_slice(), pos: 0 };
+        match r.bytes(MOVIE_MAGIC.len()) {
+            Ok(magic) if magic == MOVIE_MAGIC => (),
+            _ => return Err(~"not a movie")
+        }
+        let version = match r.number(2) { Ok(n) => n, Err(e) => return Err(e) };
+        if version > MOVIE_VERSION as u64 {
+            return Err(format!("movie version {} is newer than this emulator", version));
+        }
+        let len = match r.number(1) { Ok(n) => n as uint, Err(e) => return Err(e) };
+        let movie_model = match r.bytes(len) {
+            Ok(b) => match str::from_utf8_opt(b) {
+                Some(movie_model) => movie_model,
+                None => return Err(~"damaged movie")
+            },
+            Err(e) => return Err(e)
+        };
+        if movie_model != model {
+            return Err(format!("the movie is for the {}, not the {}", movie_model, model));
+        }
+        let len = match r.number(4) { Ok(n) => n as uint, Err(e) => return Err(e) };
+        let start = match r.bytes(len) { Ok(b) => b.to_owned(), Err(e) => return Err(e) };
+        let count = match r.number(4) { Ok(n) => n, Err(e) => return Err(e) };
+        let mut events = ~[];
+        for _ in range(0, count) {
+            let cy = match r.number(8) { Ok(n) => n, Err(e) => return Err(e) };
+            let b = match r.bytes(2) { Ok(b) => b, Err(e) => return Err(e) };
+            let event = match b[0] {
+                KEY_PRESS_KIND => KeyPress(b[1]),
+                kind => return Err(format!("unknown input kind {}", kind))
+            };
+            events.push((cy, event));
+        }
+        Ok(Movie { start: start, events: events })
+    }
+}
+
+// A movie file being read, which ends in an error rather than running off the end.
+struct MovieReader<'a> {
+    data: &'a [u8],
+    pos: uint,
+}
+
+impl<'a> MovieReader<'a> {
+    fn bytes(&mut self, n: uint) -> Result<&'a [u8], ~str> {
+        if self.data.len() - self.pos < n {
+            return Err(~"truncated movie");
+        }
+        let b = self.data.slice(self.pos, self.pos + n);
+        self.pos += n;
+        Ok(b)
+    }
+
+    // A little-endian number `n` bytes long.
+    fn number(&mut self, n: uint) -> Result<u64, ~str> {
+        self.bytes(n).map(|b| b.iter().rev().fold(0u64, |acc, &byte| (acc << 8) | byte as u64))
+    }
+}
+
+pub struct Player {
+    movie: Movie,
+    next: uint,     // the next event to deliver
+}
+
+impl Player {
+    pub fn new(movie: Movie) -> Player { Player { movie: movie, next: 0 } }
+
+    /// Puts the machine in the movie's starting state, or leaves it alone if the snapshot won't
+    /// load.
+    pub fn start(&mut self, cpu: &mut Cpu<AppleII>) -> Result<(), ~str> {
+        self.next = 0;
+        load_from_slice(cpu, self.movie.start)
+    }
+
+    /// Delivers the input that's due. Call this at every point where input could have arrived
+    /// during recording; between instructions is enough.
+    pub fn deliver(&mut self, cpu: &mut Cpu<AppleII>) {
+        while self.next < self.movie.events.len() {
+            let (cy, event) = self.movie.events[self.next];
+            if cy > cpu.cycles() {
+                break;
+            }
+            event.apply(cpu.mem_mut());
+            self.next += 1;
+        }
+    }
+
+    /// How long until the next input, so that a run can stop to deliver it.
+    pub fn cycles_to_next(&self, cpu: &Cpu<AppleII>) -> Option<Cycles> {
+        if self.finished() {
+            return None;
+        }
+        let (cy, _) = self.movie.events[self.next];
+        Some(if cy > cpu.cycles() { cy - cpu.cycles() } else { 0 })
+    }
+
+    pub fn finished(&self) -> bool { self.next == self.movie.events.len() }
+
+    /// Runs for at least `cycles` cycles, or until the CPU halts, delivering input on time.
+    pub fn run(&mut self, cpu: &mut Cpu<AppleII>, cycles: Cycles) {
+        let end = cpu.cycles() + cycles;
+        while cpu.cycles() < end && cpu.halted().is_none() {
+            self.deliver(cpu);
+            cpu.step();
+        }
+    }
+}
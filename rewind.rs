@@ -0,0 +1,149 @@
+//
+// Rewinding
+//
+// Snapshots of the machine are taken every so often as the debugger runs it. Going back restores
+// the nearest snapshot before the target and replays forward from there. That lands on the same
+// state as long as the run is deterministic; input from outside, like key presses, isn't part of
+// a snapshot and isn't replayed.
+//
+
+use extra::ringbuf::RingBuf;
+use extra::container::Deque;
+use cpu::{Cpu, Cycles};
+use mem::Mem;
+use state::{Snapshot, save_to_vec, load_from_slice};
+
+pub struct Rewind<M> {
+    interval: Cycles,
+    capacity: uint,
+    // The cycle each was taken on, and the snapshot, oldest first
+    snapshots: RingBuf<(Cycles, ~[u8])>,
+    // Instantiated in `new`, where M is known to be a Snapshot, so that the debugger can take
+    // snapshots without requiring it everywhere.
+    save: fn(&Cpu<M>) -> ~[u8],
+    load: fn(&mut Cpu<M>, &[u8]),
+}
+
+fn save_cpu<M:Mem+Snapshot>(cpu: &Cpu<M>) -> ~[u8] { save_to_vec(cpu) }
+// The snapshots are our own, so they always load.
+fn load_cpu<M:Mem+Snapshot>(cpu: &mut Cpu<M>, data: &[u8]) {
+    load_from_slice(cpu, data).unwrap()
+}
+
+impl<M:Mem+Snapshot> Rewind<M> {
+    /// Snapshots every `interval` cycles, keeping the last `capacity`.
+    pub fn new(interval: Cycles, capacity: uint) -> Rewind<M> {
+        Rewind {
+            interval: interval,
+            capacity: capacity,
+            snapshots: RingBuf::new(),
+            save: save_cpu::<M>,
+            load: load_cpu::<M>,
+        }
+    }
+}
+
+impl<M:Mem> Rewind<M> {
+    /// How far back rewinding can go.
+    pub fn oldest(&self) -> Option<Cycles> {
+        self.snapshots.front().map(|&(cy, _)| cy)
+    }
+
+    /// Forgets every snapshot, e.g. when the machine has been loaded from a save file.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Called at each instruction boundary; takes a snapshot if one is due.
+    pub fn snapshot(&mut self, cpu: &Cpu<M>) {
+        let due = match self.snapshots.back() {
+            Some(&(cy, _)) => cpu.cycles() >= cy + self.interval,
+            None => true
+        };
+        if due {
+            if self.snapshots.len() == self.capacity {
+                self.snapshots.pop_front();
+            }
+            self.snapshots.push_back((cpu.cycles(), (self.save)(cpu)));
+        }
+    }
+
+    // The most recent instruction boundary before `now` where `pred` holds, found by replaying
+    // from each snapshot in turn, newest first. This leaves the CPU somewhere in the past.
+    fn find_back(&mut self, cpu: &mut Cpu<M>, now: Cycles, pred: |&Cpu<M>| -> bool)
+                 -> Option<Cycles> {
+        let mut end = now;
+        let mut i = self.snapshots.len();
+        while i > 0 {
+            i -= 1;
+            let &(cy, ref data) = self.snapshots.get(i);
+            if cy >= end {
+                continue;
+            }
+            (self.load)(cpu, data.as_slice());
+            let mut found = None;
+            while cpu.cycles() < end {
+                if pred(&*cpu) {
+                    found = Some(cpu.cycles());
+                }
+                cpu.step();
+            }
+            if found.is_some() {
+                return found;
+            }
+            end = cy;
+        }
+        None
+    }
+
+    // Replays to the instruction boundary at `target`, from the snapshot before it.
+    fn go_to(&mut self, cpu: &mut Cpu<M>, target: Cycles) {
+        let mut i = self.snapshots.len();
+        loop {
+            i -= 1;
+            let &(cy, ref data) = self.snapshots.get(i);
+            if cy <= target {
+                (self.load)(cpu, data.as_slice());
+                break;
+            }
+        }
+        while cpu.cycles() < target {
+            cpu.step();
+        }
+        // Later snapshots are of a future that may not happen now.
+        while self.snapshots.back().map_or(false, |&(cy, _)| cy > target) {
+            self.snapshots.pop_back();
+        }
+    }
+
+    // The replays run with the tracer detached, since what they run has been traced already.
+    fn back_to(&mut self, cpu: &mut Cpu<M>, pred: |&Cpu<M>| -> bool) -> bool {
+        let now = cpu.cycles();
+        let saved = (self.save)(&*cpu);
+        let tracer = cpu.set_tracer(None);
+        let found = match self.find_back(cpu, now, pred) {
+            Some(target) => {
+                self.go_to(cpu, target);
+                true
+            }
+            None => {
+                (self.load)(cpu, saved.as_slice());
+                false
+            }
+        };
+        cpu.set_tracer(tracer);
+        found
+    }
+
+    /// Goes back to before the last instruction. Returns false, leaving the CPU as it was, if
+    /// that's earlier than the oldest snapshot.
+    pub fn step_back(&mut self, cpu: &mut Cpu<M>) -> bool {
+        self.back_to(cpu, |_| true)
+    }
+
+    /// Goes back to the last time the PC was `pc`. Returns false, leaving the CPU as it was, if
+    /// that's not since the oldest snapshot.
+    pub fn run_back_to(&mut self, cpu: &mut Cpu<M>, pc: u16) -> bool {
+        self.back_to(cpu, |cpu| cpu.pc() == pc)
+    }
+}
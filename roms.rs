@@ -0,0 +1,203 @@
+//
+// ROM images
+//
+// Finds the ROM images a machine needs along a search path, checks their sizes, and identifies
+// known dumps by the CRC-32 of each chip in them. An image that isn't recognized still loads,
+// since it may be a patched or homemade ROM; identifying it just says which one is in use.
+//
+
+use std::io;
+use std::io::File;
+use a2::{Model, ModelII, ModelIIPlus, ModelIIe, ModelIIeEnhanced};
+
+#[deriving(Eq, Clone)]
+pub enum RomKind {
+    /// $D000-$FFFF, or $C000-$FFFF on the IIe.
+    SystemRom,
+    /// The video character generator.
+    CharacterRom,
+    /// The Disk II controller's P5 PROM, its boot code at $Cn00.
+    DiskBootRom,
+    /// The Disk II controller's P6 PROM, its logic state sequencer.
+    DiskSequencerRom,
+}
+
+impl RomKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SystemRom => "system ROM",
+            CharacterRom => "character ROM",
+            DiskBootRom => "Disk II boot PROM",
+            DiskSequencerRom => "Disk II sequencer PROM",
+        }
+    }
+}
+
+/// A known good dump, as the CRC-32 of each chip in it, in address order. The chips start
+/// `offset` bytes into the image; what comes before them is empty sockets, which can be dumped as
+/// anything.
+pub struct KnownRom {
+    pub name: &'static str,
+    pub kind: RomKind,
+    pub offset: uint,
+    pub chip_size: uint,
+    pub crcs: &'static [u32],
+}
+
+pub static KNOWN_ROMS: [KnownRom, ..9] = [
+    // $D000-$DFFF was left for the Programmer's Aid ROM, which most machines didn't have
+    KnownRom { name: "Apple ][ Integer BASIC ROM", kind: SystemRom, offset: 0x1000,
+               chip_size: 0x800, crcs: &[0xc0a4ad3b, 0xa99c2cf6, 0x62230d38, 0x020a86d0] },
+    KnownRom { name: "Apple ][+ Autostart ROM", kind: SystemRom, offset: 0, chip_size: 0x800,
+               crcs: &[0x6f05f949, 0x1f08087c, 0x2b8d9a89, 0x5719871a, 0x9a04eecf, 0x079589c4] },
+    KnownRom { name: "Apple //e ROM", kind: SystemRom, offset: 0, chip_size: 0x2000,
+               crcs: &[0xe248835e, 0xfc3d59d8] },
+    KnownRom { name: "Enhanced Apple //e ROM", kind: SystemRom, offset: 0, chip_size: 0x2000,
+               crcs: &[0x443aa7c4, 0x95e10034] },
+    KnownRom { name: "Apple ][+ character ROM", kind: CharacterRom, offset: 0, chip_size: 0x800,
+               crcs: &[0x64f415c6] },
+    KnownRom { name: "Apple //e character ROM", kind: CharacterRom, offset: 0,
+               chip_size: 0x1000, crcs: &[0xb081df66] },
+    KnownRom { name: "Enhanced Apple //e character ROM", kind: CharacterRom, offset: 0,
+               chip_size: 0x1000, crcs: &[0x2651014d] },
+    KnownRom { name: "Disk II 16-sector boot PROM (341-0027)", kind: DiskBootRom, offset: 0,
+               chip_size: 0x100, crcs: &[0xce7144f6] },
+    KnownRom { name: "Disk II 16-sector sequencer PROM (341-0028)", kind: DiskSequencerRom,
+               offset: 0, chip_size: 0x100, crcs: &[0xb72a2c70] },
+];
+
+/// The CRC-32 used by zip, PNG and ROM catalogues.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data.iter() {
+        crc ^= *b as u32;
+        for _ in range(0, 8) {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The known dump an image is, if any.
+pub fn identify(kind: RomKind, data: &[u8]) -> Option<&'static KnownRom> {
+    KNOWN_ROMS.iter().find(|known| {
+        let chips = data.slice_from(known.offset.min(&data.len()));
+        known.kind == kind && data.len() == known.offset + known.chip_size * known.crcs.len() &&
+            chips.chunks(known.chip_size).zip(known.crcs.iter()).all(|(chip, crc)| {
+                crc32(chip) == *crc
+            })
+    })
+}
+
+/// The names a ROM goes by, most common first.
+pub fn filenames(kind: RomKind, model: Model) -> ~[&'static str] {
+    match (kind, model) {
+        (SystemRom, ModelII) => ~["apple2o.rom", "apple2orig.rom"],
+        (SystemRom, ModelIIPlus) => ~["apple2.rom", "apple2plus.rom", "apple2p.rom"],
+        (SystemRom, ModelIIe) => ~["apple2e.rom"],
+        (SystemRom, ModelIIeEnhanced) => ~["apple2ee.rom", "apple2e_enhanced.rom"],
+        (CharacterRom, ModelIIe) => ~["apple2e.chr", "342-0133-a.chr"],
+        (CharacterRom, ModelIIeEnhanced) => ~["apple2ee.chr", "342-0265-a.chr"],
+        (CharacterRom, _) => ~["apple2.chr", "341-0036.chr"],
+        (DiskBootRom, _) => ~["disk2.rom", "341-0027-a.p5"],
+        (DiskSequencerRom, _) => ~["disk2_p6.rom", "341-0028-a.rom"],
+    }
+}
+
+/// How big an image has to be.
+pub fn expected_size(kind: RomKind, model: Model) -> uint {
+    match kind {
+        SystemRom => model.rom_size(),
+        CharacterRom => if model.is_iie() { 0x1000 } else { 0x800 },
+        DiskBootRom | DiskSequencerRom => 0x100,
+    }
+}
+
+/// A ROM image that's been loaded.
+pub struct Rom {
+    pub kind: RomKind,
+    pub path: Path,
+    pub data: ~[u8],
+    /// Which dump it is, if it's one that's known.
+    pub known: Option<&'static KnownRom>,
+}
+
+impl Rom {
+    /// Which dump it is.
+    pub fn name(&self) -> ~str {
+        match self.known {
+            Some(known) => known.name.to_owned(),
+            None => format!("unrecognized {}, CRC-32 {:08x}", self.kind.name(), crc32(self.data))
+        }
+    }
+
+    /// Which dump it is, and the file.
+    pub fn describe(&self) -> ~str {
+        format!("{} ({})", self.name(), self.path.display())
+    }
+}
+
+/// Where to look for ROMs, in order.
+pub struct RomSearch {
+    pub paths: ~[Path],
+}
+
+impl RomSearch {
+    /// The current directory, then `roms` in it.
+    pub fn new() -> RomSearch {
+        RomSearch::with_paths(~[Path::new("."), Path::new("roms")])
+    }
+
+    pub fn with_paths(paths: ~[Path]) -> RomSearch {
+        RomSearch { paths: paths }
+    }
+
+    /// The first file by one of a model's ROM's names, looking in each directory in turn.
+    pub fn find(&self, kind: RomKind, model: Model) -> Option<Path> {
+        let names = filenames(kind, model);
+        for dir in self.paths.iter() {
+            for name in names.iter() {
+                let path = dir.join(*name);
+                if path.exists() {
+                    return Some(path);
+                }
+            }
+        }
+        None
+    }
+
+    /// Loads a model's ROM from the file `find` finds. A file of the wrong size is an error
+    /// rather than being skipped, so that it doesn't go unnoticed.
+    pub fn load(&self, kind: RomKind, model: Model) -> Result<Rom, ~str> {
+        match self.find(kind, model) {
+            Some(path) => return load_file(kind, model, path),
+            None => ()
+        }
+        let names = filenames(kind, model);
+        let dirs: ~[~str] = self.paths.iter().map(|p| p.display().to_str()).collect();
+        Err(format!("no {} {} found: looked for {} in {}", model.name(), kind.name(),
+                    names.connect(", "), dirs.connect(", ")))
+    }
+}
+
+/// Loads a model's ROM from a file.
+pub fn load_file(kind: RomKind, model: Model, path: Path) -> Result<Rom, ~str> {
+    let data = io::io_error::cond.trap(|_| {}).inside(|| {
+        let mut file = File::open(&path);
+        match file {
+            Some(ref mut file) => Some(file.read_to_end()),
+            None => None
+        }
+    });
+    let data = match data {
+        Some(data) => data,
+        None => return Err(format!("can't read {}", path.display()))
+    };
+    let size = expected_size(kind, model);
+    if data.len() != size {
+        return Err(format!("{} is {} bytes, but the {} {} is {}", path.display(), data.len(),
+                           model.name(), kind.name(), size));
+    }
+    let known = identify(kind, data);
+    Ok(Rom { kind: kind, path: path, data: data, known: known })
+}
@@ -0,0 +1,188 @@
+//
+// Machine state snapshots and save files
+//
+// A snapshot is everything that decides how the machine runs on from an instruction boundary.
+// Configuration isn't part of it: the CPU variant, which cards are in which slots and which disk
+// images are loaded all have to match when a snapshot is loaded.
+//
+// Snapshots are made of chunks, each a four-character tag, a version byte, a 32-bit little-endian
+// length and the contents. Loading skips chunks it doesn't know, and a chunk's version only goes
+// up when fields are added to the end of it, which older loaders ignore. So a snapshot from a
+// newer emulator still loads, as long as what it adds doesn't matter to this one.
+//
+// Loading checks a snapshot against the machine before changing anything, so a damaged or
+// mismatched one is an error that leaves the machine as it was.
+//
+// A save file is a header followed by a snapshot. The header is `SAVE_MAGIC`, the file format
+// version as a 16-bit little-endian number, and the machine model as a length-prefixed string.
+//
+
+use std::io::{Reader, Writer};
+use std::io::Decorator;
+use std::io::mem::{MemWriter, BufReader};
+use std::str;
+
+pub static SAVE_MAGIC: &'static [u8] = bytes!("A2STATE", 0x1a);
+/// Bumped only for changes older loaders can't skip over.
+pub static SAVE_VERSION: u16 = 1;
+
+// The tag, version and length
+static CHUNK_HEADER_LEN: uint = 9;
+
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    /// Checks that `load_state` can load the snapshot: that it has every chunk this needs, at
+    /// least as long as it reads, and that it matches this configuration.
+    fn check_state(&self, _: &StateReader) -> Result<(), ~str> { Ok(()) }
+    /// Loads a snapshot that `check_state` has passed.
+    fn load_state(&mut self, r: &StateReader);
+}
+
+pub struct StateWriter {
+    out: MemWriter,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter { StateWriter { out: MemWriter::new() } }
+
+    /// Adds a chunk, with `f` writing its contents.
+    pub fn chunk(&mut self, tag: &str, version: u8, f: |&mut MemWriter|) {
+        assert!(tag.len() == 4);
+        let mut contents = MemWriter::new();
+        f(&mut contents);
+        let contents = contents.inner();
+        self.out.write(tag.as_bytes());
+        self.out.write_u8(version);
+        self.out.write_le_u32(contents.len() as u32);
+        self.out.write(contents.as_slice());
+    }
+
+    pub fn finish(self) -> ~[u8] { self.out.inner() }
+}
+
+struct Chunk {
+    tag: ~str,
+    version: u8,
+    contents: ~[u8],
+}
+
+pub struct StateReader {
+    chunks: ~[Chunk],
+}
+
+impl StateReader {
+    /// Splits a snapshot into its chunks.
+    pub fn new(data: &[u8]) -> Result<StateReader, ~str> {
+        let mut chunks = ~[];
+        let mut pos = 0;
+        while pos < data.len() {
+            if data.len() - pos < CHUNK_HEADER_LEN {
+                return Err(~"truncated snapshot");
+            }
+            let tag = match str::from_utf8_opt(data.slice(pos, pos + 4)) {
+                Some(tag) => tag.to_owned(),
+                None => return Err(~"damaged snapshot")
+            };
+            let mut header = BufReader::new(data.slice(pos + 4, pos + CHUNK_HEADER_LEN));
+            let version = header.read_u8();
+            let len = header.read_le_u32() as uint;
+            let start = pos + CHUNK_HEADER_LEN;
+            if data.len() - start < len {
+                return Err(~"truncated snapshot");
+            }
+            chunks.push(Chunk {
+                tag: tag,
+                version: version,
+                contents: data.slice(start, start + len).to_owned(),
+            });
+            pos = start + len;
+        }
+        Ok(StateReader { chunks: chunks })
+    }
+
+    /// The version and contents of the chunk with the tag, if there is one.
+    pub fn chunk<'a>(&'a self, tag: &str) -> Option<(u8, BufReader<'a>)> {
+        self.chunks.iter().find(|c| c.tag.as_slice() == tag).map(|c| {
+            (c.version, BufReader::new(c.contents.as_slice()))
+        })
+    }
+
+    /// The contents of the chunk with the tag, if there is one.
+    pub fn contents<'a>(&'a self, tag: &str) -> Option<&'a [u8]> {
+        self.chunks.iter().find(|c| c.tag.as_slice() == tag).map(|c| c.contents.as_slice())
+    }
+
+    /// Checks that there's a chunk with the tag, of at least `len` bytes.
+    pub fn require(&self, tag: &str, len: uint) -> Result<(), ~str> {
+        match self.contents(tag) {
+            Some(contents) if contents.len() >= len => Ok(()),
+            Some(_) => Err(format!("the snapshot's {} chunk is truncated", tag)),
+            None => Err(format!("the snapshot has no {} chunk", tag))
+        }
+    }
+
+    /// The contents of a chunk that has to be there, as `check_state` has made sure.
+    pub fn expect<'a>(&'a self, tag: &str) -> BufReader<'a> {
+        match self.chunk(tag) {
+            Some((_, r)) => r,
+            None => fail!("the snapshot has no {} chunk", tag)
+        }
+    }
+}
+
+pub fn save_to_vec<S:Snapshot>(x: &S) -> ~[u8] {
+    let mut w = StateWriter::new();
+    x.save_state(&mut w);
+    w.finish()
+}
+
+/// Loads a snapshot, or leaves `x` alone if it can't.
+pub fn load_from_slice<S:Snapshot>(x: &mut S, data: &[u8]) -> Result<(), ~str> {
+    let state = match StateReader::new(data) {
+        Ok(state) => state,
+        Err(e) => return Err(e)
+    };
+    match x.check_state(&state) {
+        Ok(()) => {
+            x.load_state(&state);
+            Ok(())
+        }
+        Err(e) => Err(e)
+    }
+}
+
+/// Writes a save file for a machine of the given model.
+pub fn write_save_file<S:Snapshot>(w: &mut Writer, model: &str, x: &S) {
+    w.write(SAVE_MAGIC);
+    w.write_le_u16(SAVE_VERSION);
+    w.write_u8(model.len() as u8);
+    w.write(model.as_bytes());
+    w.write(save_to_vec(x).as_slice());
+}
+
+/// Loads a save file into a machine of the given model. An error means nothing was loaded.
+pub fn read_save_file<S:Snapshot>(r: &mut Reader, model: &str, x: &mut S) -> Result<(), ~str> {
+    let data = r.read_to_end();
+    // the magic, the version and the model's length
+    let fixed = SAVE_MAGIC.len() + 3;
+    if data.len() < fixed || data.slice_to(SAVE_MAGIC.len()) != SAVE_MAGIC {
+        return Err(~"not a save file");
+    }
+    let mut header = BufReader::new(data.slice(SAVE_MAGIC.len(), fixed));
+    let version = header.read_le_u16();
+    if version > SAVE_VERSION {
+        return Err(format!("save file version {} is newer than this emulator", version));
+    }
+    let len = header.read_u8() as uint;
+    if data.len() < fixed + len {
+        return Err(~"truncated save file");
+    }
+    let saved_model = match str::from_utf8_opt(data.slice(fixed, fixed + len)) {
+        Some(saved_model) => saved_model,
+        None => return Err(~"damaged save file")
+    };
+    if saved_model != model {
+        return Err(format!("the save file is for the {}, not the {}", saved_model, model));
+    }
+    load_from_slice(x, data.slice_from(fixed + len))
+}
@@ -1,9 +1,33 @@
 
-use cpu::Cpu;
-use mem::Mem;
-use a2::AppleII;
+use cpu::{Cpu, CpuVariant, Nmos6502, Cmos65C02, Regs};
+use cpu::{EmulateUnstable, TrapUnstable, WarnUnstable, Jammed, Trapped};
+use cpu::{RanCycles, ReachedPc, ConditionMet, Halted};
+use mem::{Mem, BusHook, BusAccess};
+use a2::{AppleII, CYCLES_PER_FRAME};
+use a2::{Model, ModelII, ModelIIPlus, ModelIIe, ModelIIeEnhanced};
 use a2::Peripheral;
 use diskii::DiskController;
+use disasm::disassemble_range;
+use debugger::{Debugger, Always, RegisterIs, RegX, WatchWrite};
+use debugger::{StepDone, HitBreakpoint, HitWatchpoint, OutOfCycles};
+use monitor::{Monitor, MonitorReply, Print, Go, Quit};
+use gdbstub::GdbStub;
+use rewind::Rewind;
+use state::{Snapshot, StateWriter, StateReader, save_to_vec, load_from_slice};
+use state::{write_save_file, read_save_file};
+use trace::{Tracer, TraceFilter, TRACE_MAGIC, TRACE_VERSION, read_log};
+use movie::{Movie, Player, KeyPress};
+use roms::{RomSearch, SystemRom, DiskBootRom, crc32, identify};
+use std::io;
+use std::io::File;
+use std::io::fs;
+use std::os;
+use std::io::Decorator;
+use std::io::mem::{MemReader, MemWriter};
+use std::str;
+use std::vec;
+use extra::json;
+use extra::json::Json;
 
 //
 
@@ -34,19 +58,1596 @@ fn test_cpu()
     cpu.step();
 }
 
+// Loads a program at $0200 in a fresh Ram, points the reset vector at it and runs it to the end.
+fn run_program(prog: &[u8]) -> Cpu<Ram>
+{
+    run_program_on(Nmos6502, prog)
+}
+
+fn run_program_on(variant: CpuVariant, prog: &[u8]) -> Cpu<Ram>
+{
+    let mut ram = Ram { mem: [ 0, ..0x800 ] };
+    for i in range(0, prog.len()) {
+        ram.mem[0x200 + i] = prog[i];
+    }
+    // $FFFC mirrors down to $07FC
+    ram.mem[0x7fc] = 0x00;
+    ram.mem[0x7fd] = 0x02;
+    let mut cpu = Cpu::with_variant(ram, variant);
+    cpu.reset();
+    while cpu.regs.pc < 0x200 + prog.len() as u16 {
+        cpu.step();
+    }
+    cpu
+}
+
+#[test]
+fn test_decimal_adc()
+{
+    // SED; CLC; LDA #$58; ADC #$46
+    let cpu = run_program([0xf8, 0x18, 0xa9, 0x58, 0x69, 0x46]);
+    assert!(cpu.regs.a == 0x04);
+    assert!(cpu.regs.flags & 0x01 != 0);
+
+    // SED; SEC; LDA #$12; ADC #$34
+    let cpu = run_program([0xf8, 0x38, 0xa9, 0x12, 0x69, 0x34]);
+    assert!(cpu.regs.a == 0x47);
+    assert!(cpu.regs.flags & 0x01 == 0);
+}
+
+#[test]
+fn test_decimal_adc_flags()
+{
+    // SED; CLC; LDA #$99; ADC #$01 -- Z comes from the binary sum ($9A), N from the
+    // unadjusted high nibble
+    let cpu = run_program([0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01]);
+    assert!(cpu.regs.a == 0x00);
+    assert!(cpu.regs.flags & 0x01 != 0);
+    assert!(cpu.regs.flags & 0x02 == 0);
+    assert!(cpu.regs.flags & 0x80 != 0);
+
+    // SED; CLC; LDA #$79; ADC #$10 -- V is set as for a signed overflow on the adjusted result
+    let cpu = run_program([0xf8, 0x18, 0xa9, 0x79, 0x69, 0x10]);
+    assert!(cpu.regs.a == 0x89);
+    assert!(cpu.regs.flags & 0x40 != 0);
+}
+
+#[test]
+fn test_decimal_sbc()
+{
+    // SED; SEC; LDA #$46; SBC #$12
+    let cpu = run_program([0xf8, 0x38, 0xa9, 0x46, 0xe9, 0x12]);
+    assert!(cpu.regs.a == 0x34);
+    assert!(cpu.regs.flags & 0x01 != 0);
+
+    // SED; SEC; LDA #$00; SBC #$01 -- borrows, flags as for binary $FF
+    let cpu = run_program([0xf8, 0x38, 0xa9, 0x00, 0xe9, 0x01]);
+    assert!(cpu.regs.a == 0x99);
+    assert!(cpu.regs.flags & 0x01 == 0);
+    assert!(cpu.regs.flags & 0x80 != 0);
+
+    // SED; CLC; LDA #$32; SBC #$02 -- carry clear subtracts one more
+    let cpu = run_program([0xf8, 0x18, 0xa9, 0x32, 0xe9, 0x02]);
+    assert!(cpu.regs.a == 0x29);
+}
+
+// Runs a program like run_program, returning the cycles taken by its last instruction.
+fn last_step_cycles(prog: &[u8], x: u8) -> u64
+{
+    let mut cpu = run_program([0xa2, x]);
+    for i in range(0, prog.len()) {
+        cpu.mem.mem[0x202 + i] = prog[i];
+    }
+    while cpu.regs.pc < 0x202 + prog.len() as u16 {
+        let cy = cpu.cy;
+        cpu.step();
+        if cpu.regs.pc >= 0x202 + prog.len() as u16 {
+            return cpu.cy - cy;
+        }
+    }
+    fail!("program ended early")
+}
+
+#[test]
+fn test_page_cross_cycles()
+{
+    // LDA $0300,X / LDA $02FF,X
+    assert!(last_step_cycles([0xbd, 0x00, 0x03], 0x01) == 4);
+    assert!(last_step_cycles([0xbd, 0xff, 0x02], 0x01) == 5);
+    // STA $02FF,X always takes 5
+    assert!(last_step_cycles([0x9d, 0xff, 0x02], 0x01) == 5);
+    assert!(last_step_cycles([0x9d, 0x00, 0x03], 0x01) == 5);
+    // INC $02FF,X always takes 7
+    assert!(last_step_cycles([0xfe, 0xff, 0x02], 0x01) == 7);
+    // LDA ($10),Y with $10 = $02FF, after LDY #$01
+    assert!(last_step_cycles([0xa0, 0x01, 0xa9, 0xff, 0x85, 0x10, 0xa9, 0x02, 0x85, 0x11,
+                              0xb1, 0x10], 0x00) == 6);
+}
+
+#[test]
+fn test_branch_cycles()
+{
+    // BNE not taken (X = 0 sets Z), taken, and taken across a page
+    assert!(last_step_cycles([0xd0, 0x02], 0x00) == 2);
+    assert!(last_step_cycles([0xd0, 0x00], 0x01) == 3);
+    let mut cpu = run_program([0xa2, 0x01, 0xd0, 0x7f]);
+    let cy = cpu.cy;
+    cpu.regs.pc = 0x202;
+    cpu.step();
+    assert!(cpu.regs.pc == 0x283);
+    assert!(cpu.cy - cy == 3);
+    cpu.regs.pc = 0x2f0;
+    cpu.mem.mem[0x2f0] = 0xd0;
+    cpu.mem.mem[0x2f1] = 0x10;
+    cpu.step();
+    assert!(cpu.regs.pc == 0x302);
+    assert!(cpu.cy - cy == 7);
+}
+
+#[test]
+fn test_no_dma_register()
+{
+    // LDA #$02; STA $4014 -- ordinary RAM on the Apple II, no OAM DMA
+    let cpu = run_program([0xa9, 0x02, 0x8d, 0x14, 0x40]);
+    assert!(cpu.mem.mem[0x4014 & 0x7ff] == 0x02);
+    assert!(cpu.mem.mem[0x2004 & 0x7ff] == 0x00);
+    assert!(cpu.cy == 7 + 6);
+}
+
+// Latches writes to one address, and reads back what it latched, $5A to start with.
+struct LatchHook { latch: u8 }
+
+impl BusHook for LatchHook {
+    fn loadb(&mut self, addr: u16) -> Option<u8> {
+        if addr == 0x400 { Some(self.latch) } else { None }
+    }
+    fn storeb(&mut self, addr: u16, val: u8) -> bool {
+        if addr == 0x400 { self.latch = val; true } else { false }
+    }
+}
+
+#[test]
+fn test_bus_hook()
+{
+    let mut cpu = run_program([]);
+    cpu.add_hook(0x400, 0x4ff, ~LatchHook { latch: 0x5a });
+    // LDA $0400; EOR #$FF; STA $0400; LDA #$00; LDA $0400; STA $0401
+    let prog = [0xad, 0x00, 0x04, 0x49, 0xff, 0x8d, 0x00, 0x04, 0xa9, 0x00,
+                0xad, 0x00, 0x04, 0x8d, 0x01, 0x04];
+    for i in range(0, prog.len()) {
+        cpu.mem.mem[0x200 + i] = prog[i];
+    }
+    cpu.step();
+    assert!(cpu.regs.a == 0x5a);
+    for _ in range(0, 5) {
+        cpu.step();
+    }
+    // The store went to the hook, which hands it back, and the one past it to memory
+    assert!(cpu.regs.a == 0xa5);
+    assert!(cpu.mem.mem[0x400] == 0x00);
+    assert!(cpu.mem.mem[0x401] == 0xa5);
+}
+
+#[test]
+fn test_disassemble_range()
+{
+    let mut ram = Ram { mem: [ 0, ..0x800 ] };
+    let prog = [0xa9, 0x00, 0x9d, 0x00, 0x04, 0xd0, 0xfb, 0x6c, 0xfc, 0xff, 0x60];
+    for i in range(0, prog.len()) {
+        ram.mem[0x300 + i] = prog[i];
+    }
+    let lines = disassemble_range(&mut ram, 0x300, 0x30a, Nmos6502);
+    assert!(lines.len() == 5);
+    assert!(lines[0] == ~"0300  A9 00     LDA #$00");
+    assert!(lines[1] == ~"0302  9D 00 04  STA $0400,X");
+    assert!(lines[2] == ~"0305  D0 FB     BNE $0302");
+    assert!(lines[3] == ~"0307  6C FC FF  JMP ($FFFC)");
+    assert!(lines[4] == ~"030A  60        RTS");
+    assert!(disassemble_range(&mut ram, 0x30a, 0x300, Nmos6502).len() == 0);
+
+    // Disassembling the soft switches doesn't flip them
+    let mut a2 = AppleII::new();
+    let status = a2.status();
+    disassemble_range(&mut a2, 0xc050, 0xc08f, Nmos6502);
+    assert!(a2.status() == status);
+}
+
+#[test]
+fn test_65c02_instructions()
+{
+    // LDA #$F0; STA $10; LDX #$11; LDY #$22; PHX; PHY; PLX; PLY; STZ $11; LDA #$30; TSB $10;
+    // INC A; BIT #$00
+    let cpu = run_program_on(Cmos65C02, [0xa9, 0xf0, 0x85, 0x10, 0xa2, 0x11, 0xa0, 0x22,
+                                         0xda, 0x5a, 0xfa, 0x7a, 0x64, 0x11, 0xa9, 0x30,
+                                         0x04, 0x10, 0x1a, 0x89, 0x00]);
+    assert!(cpu.regs.x == 0x22);
+    assert!(cpu.regs.y == 0x11);
+    assert!(cpu.mem.mem[0x10] == 0xf0 | 0x30);
+    assert!(cpu.mem.mem[0x11] == 0x00);
+    assert!(cpu.regs.a == 0x31);
+    assert!(cpu.regs.flags & 0x02 != 0);
+
+    // LDA #$30; STA $12; LDA #$04; STA $13; LDA #$7F; STA ($12); LDA #$21; TRB $0430;
+    // LDA ($12)
+    let cpu = run_program_on(Cmos65C02, [0xa9, 0x30, 0x85, 0x12, 0xa9, 0x04, 0x85, 0x13,
+                                         0xa9, 0x7f, 0x92, 0x12, 0xa9, 0x21, 0x1c, 0x30,
+                                         0x04, 0xb2, 0x12]);
+    assert!(cpu.mem.mem[0x430] == 0x5e);
+    assert!(cpu.regs.a == 0x5e);
+}
+
+#[test]
+fn test_65c02_jmp_indirect()
+{
+    // JMP ($02FF), with the vector straddling a page
+    let mut cpu = run_program_on(Cmos65C02, [0xa9, 0x00]);
+    cpu.mem.mem[0x2ff] = 0x34;
+    cpu.mem.mem[0x300] = 0x12;
+    cpu.mem.mem[0x200] = 0x56;
+    cpu.regs.pc = 0x202;
+    cpu.mem.mem[0x202] = 0x6c;
+    cpu.mem.mem[0x203] = 0xff;
+    cpu.mem.mem[0x204] = 0x02;
+    cpu.step();
+    assert!(cpu.regs.pc == 0x1234);
+
+    // ...whereas the NMOS part wraps within the page
+    let mut cpu = run_program_on(Nmos6502, [0xa9, 0x00]);
+    cpu.mem.mem[0x2ff] = 0x34;
+    cpu.mem.mem[0x300] = 0x12;
+    cpu.mem.mem[0x200] = 0x56;
+    cpu.regs.pc = 0x202;
+    cpu.mem.mem[0x202] = 0x6c;
+    cpu.mem.mem[0x203] = 0xff;
+    cpu.mem.mem[0x204] = 0x02;
+    cpu.step();
+    assert!(cpu.regs.pc == 0x5634);
+}
+
+#[test]
+fn test_65c02_decimal_flags()
+{
+    // SED; CLC; LDA #$99; ADC #$01 -- N and Z reflect the decimal result on the 65C02
+    let cpu = run_program_on(Cmos65C02, [0xf8, 0x18, 0xa9, 0x99, 0x69, 0x01]);
+    assert!(cpu.regs.a == 0x00);
+    assert!(cpu.regs.flags & 0x01 != 0);
+    assert!(cpu.regs.flags & 0x02 != 0);
+    assert!(cpu.regs.flags & 0x80 == 0);
+
+    // SED; SEC; LDA #$00; SBC #$01
+    let cpu = run_program_on(Cmos65C02, [0xf8, 0x38, 0xa9, 0x00, 0xe9, 0x01]);
+    assert!(cpu.regs.a == 0x99);
+    assert!(cpu.regs.flags & 0x80 != 0);
+}
+
+#[test]
+fn test_undocumented_lax_sax()
+{
+    // LDA #$C3; STA $10; LAX $10; LDA #$0F; SAX $11
+    let cpu = run_program([0xa9, 0xc3, 0x85, 0x10, 0xa7, 0x10, 0xa9, 0x0f, 0x87, 0x11]);
+    assert!(cpu.regs.x == 0xc3);
+    assert!(cpu.mem.mem[0x11] == 0x03);
+}
+
+#[test]
+fn test_undocumented_rmw()
+{
+    // LDA #$40; STA $10; LDA #$01; SLO $10 -- $10 = $80, A = $81
+    let cpu = run_program([0xa9, 0x40, 0x85, 0x10, 0xa9, 0x01, 0x07, 0x10]);
+    assert!(cpu.mem.mem[0x10] == 0x80);
+    assert!(cpu.regs.a == 0x81);
+
+    // LDA #$05; STA $10; DCP $10 -- $10 = $04, which A ($05) is greater than
+    let cpu = run_program([0xa9, 0x05, 0x85, 0x10, 0xc7, 0x10]);
+    assert!(cpu.mem.mem[0x10] == 0x04);
+    assert!(cpu.regs.flags & 0x01 != 0);
+    assert!(cpu.regs.flags & 0x02 == 0);
+
+    // LDA #$0F; STA $10; LDA #$20; SEC; ISC $10 -- $10 = $10, A = $10
+    let cpu = run_program([0xa9, 0x0f, 0x85, 0x10, 0xa9, 0x20, 0x38, 0xe7, 0x10]);
+    assert!(cpu.mem.mem[0x10] == 0x10);
+    assert!(cpu.regs.a == 0x10);
+}
+
+#[test]
+fn test_undocumented_nop_lengths()
+{
+    // NOP #$xx; NOP $xx; NOP $xx,X; NOP $xxxx; NOP $xxxx,X; NOP; LDA #$42
+    let cpu = run_program([0x80, 0xa9, 0x04, 0xa9, 0x14, 0xa9, 0x0c, 0xa9, 0xa9,
+                           0x1c, 0xa9, 0xa9, 0x1a, 0xa9, 0x42]);
+    assert!(cpu.regs.a == 0x42);
+    assert!(cpu.cy == 7 + 2 + 3 + 4 + 4 + 4 + 2 + 2);
+}
+
+#[test]
+fn test_unstable_policy()
+{
+    // LDA #$00; LDX #$0F; LXA #$3C
+    let prog = [0xa9, 0x00, 0xa2, 0x0f, 0xab, 0x3c];
+    let cpu = run_program(prog);
+    assert!(cpu.regs.a == 0x2c && cpu.regs.x == 0x2c);
+
+    let mut cpu = run_program([]);
+    for i in range(0, prog.len()) {
+        cpu.mem.mem[0x200 + i] = prog[i];
+    }
+    cpu.set_unstable_policy(WarnUnstable);
+    for _ in range(0, 3) {
+        cpu.step();
+    }
+    assert!(cpu.regs.a == 0x00 && cpu.regs.x == 0x0f);
+    assert!(cpu.regs.pc == 0x206);
+    assert!(cpu.halted().is_none());
+
+    cpu.regs.pc = 0x200;
+    cpu.set_unstable_policy(TrapUnstable);
+    for _ in range(0, 4) {
+        cpu.step();
+    }
+    assert!(cpu.halted() == Some(Trapped(0xab)));
+    assert!(cpu.regs.pc == 0x204);
+
+    cpu.set_unstable_policy(EmulateUnstable);
+    cpu.reset();
+    assert!(cpu.halted().is_none());
+}
+
+#[test]
+fn test_jam()
+{
+    let mut cpu = run_program([0xa9, 0x01]);
+    cpu.mem.mem[0x202] = 0x02;
+    cpu.step();
+    cpu.step();
+    assert!(cpu.halted() == Some(Jammed(0x02)));
+    assert!(cpu.regs.pc == 0x202);
+}
+
+// A flat 64K of RAM, for running whole-program test suites.
+pub struct FlatRam { mem: ~[u8] }
+
+impl Mem for FlatRam {
+    fn loadb(&mut self, addr: u16) -> u8     { self.mem[addr] }
+    fn storeb(&mut self, addr: u16, val: u8) { self.mem[addr] = val }
+}
+
+// Test suites too big to keep in the repository are #[ignore]d: put their files in the current
+// directory and run them with `--ignored`. Asked for without them, they fail saying what's missing.
+fn require_test_data(path: &str)
+{
+    if !Path::new(path).exists() {
+        fail!("{} isn't here: put it in the current directory to run this test", path);
+    }
+}
+
+// Loads a binary image into a FlatRam at the given address.
+fn load_flat_ram(filename: &str, addr: uint) -> FlatRam
+{
+    let image = File::open(&Path::new(filename)).read_to_end();
+    assert!(image.len() > 0 && addr + image.len() <= 0x10000);
+    let mut ram = FlatRam { mem: vec::from_elem(0x10000, 0u8) };
+    for i in range(0, image.len()) {
+        ram.mem[addr + i] = image[i];
+    }
+    ram
+}
+
+// Steps until the PC traps in a jump or branch to itself, and returns where.
+fn run_until_trap(cpu: &mut Cpu<FlatRam>, max_cycles: u64) -> u16
+{
+    loop {
+        let pc = cpu.regs.pc;
+        cpu.step();
+        if cpu.regs.pc == pc {
+            return pc;
+        }
+        if cpu.cy > max_cycles {
+            fail!("no trap after {} cycles, PC = {:04x}", max_cycles, pc);
+        }
+    }
+}
+
+// Klaus Dormann's 6502 functional test, as the stock 64K image from his repository (loaded at
+// $0000, started at $0400). Every failure traps somewhere other than the success loop.
+static FUNCTIONAL_TEST: &'static str = "6502_functional_test.bin";
+static FUNCTIONAL_TEST_SUCCESS: u16 = 0x3469;
+
+// The companion decimal mode test, assembled at $0200 with `end_of_test` as a `jmp *`. It
+// leaves 0 in ERROR ($000B) if every ADC and SBC result and flag matched.
+static DECIMAL_TEST: &'static str = "6502_decimal_test.bin";
+static DECIMAL_TEST_ERROR: u16 = 0x000b;
+
+// Flat 64K of RAM with interrupt lines that a test can raise and lower.
+pub struct LineRam { mem: ~[u8], irq: bool, nmi: bool }
+
+impl Mem for LineRam {
+    fn loadb(&mut self, addr: u16) -> u8     { self.mem[addr] }
+    fn storeb(&mut self, addr: u16, val: u8) { self.mem[addr] = val }
+    fn irq_line(&mut self) -> bool           { self.irq }
+    fn nmi_line(&mut self) -> bool           { self.nmi }
+}
+
+impl Snapshot for LineRam {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.chunk("RAM ", 1, |w| w.write(self.mem.as_slice()))
+    }
+    fn load_state(&mut self, r: &StateReader) {
+        self.mem = r.expect("RAM ").read_bytes(0x10000)
+    }
+}
+
+// NOPs everywhere, starting at $0200, with NMI going to $0400 and IRQ to $0300.
+fn line_ram_cpu() -> Cpu<LineRam>
+{
+    let mut ram = LineRam { mem: vec::from_elem(0x10000, 0xeau8), irq: false, nmi: false };
+    ram.mem[0xfffa] = 0x00; ram.mem[0xfffb] = 0x04;
+    ram.mem[0xfffe] = 0x00; ram.mem[0xffff] = 0x03;
+    let mut cpu = Cpu::new(ram);
+    cpu.set_s(0xfd);
+    cpu.set_pc(0x0200);
+    cpu
+}
+
+#[test]
+fn test_irq_line()
+{
+    let mut cpu = line_ram_cpu();
+    cpu.mem_mut().mem[0x0200] = 0x58;   // CLI
+    cpu.mem_mut().irq = true;
+    // Masked until CLI has run, and the instruction after it runs too, since CLI clears I after
+    // the interrupt poll
+    cpu.step();
+    assert!(cpu.pc() == 0x0201 && cpu.cycles() == 2);
+    cpu.step();
+    assert!(cpu.pc() == 0x0202 && cpu.cycles() == 4);
+    cpu.step();
+    assert!(cpu.pc() == 0x0300 && cpu.cycles() == 11);
+    assert!(cpu.s() == 0xfa && (cpu.p() & 0x04) != 0);
+    assert!(cpu.mem().mem[0x01fd] == 0x02 && cpu.mem().mem[0x01fc] == 0x02);
+    // Pushed with B clear
+    assert!(cpu.mem().mem[0x01fb] == 0x20);
+    // The handler runs with I set, so the held line doesn't retrigger.
+    cpu.step();
+    assert!(cpu.pc() == 0x0301);
+
+    // Likewise SEI sets I too late to stop the next poll, so CLI; SEI lets one interrupt in
+    let mut cpu = line_ram_cpu();
+    cpu.mem_mut().mem[0x0200] = 0x58;   // CLI
+    cpu.mem_mut().mem[0x0201] = 0x78;   // SEI
+    cpu.mem_mut().irq = true;
+    cpu.step();
+    cpu.step();
+    assert!(cpu.pc() == 0x0202);
+    cpu.step();
+    assert!(cpu.pc() == 0x0300 && cpu.mem().mem[0x01fc] == 0x02);
+}
+
+#[test]
+fn test_nmi_line()
+{
+    let mut cpu = line_ram_cpu();
+    cpu.mem_mut().nmi = true;
+    cpu.step();
+    assert!(cpu.pc() == 0x0400 && cpu.cycles() == 7);
+    assert!((cpu.p() & 0x04) != 0);
+    assert!((cpu.mem().mem[0x01fb] & 0x10) == 0);
+    // Holding the line doesn't retrigger; only a new rising edge does.
+    cpu.step();
+    assert!(cpu.pc() == 0x0401);
+    cpu.mem_mut().nmi = false;
+    cpu.step();
+    assert!(cpu.pc() == 0x0402);
+    cpu.mem_mut().nmi = true;
+    cpu.step();
+    assert!(cpu.pc() == 0x0400 && cpu.s() == 0xf7);
+
+    // A line raised while the CPU is jammed has been seen by the time a reset restarts it.
+    let mut cpu = line_ram_cpu();
+    cpu.mem_mut().mem[0x0200] = 0x02;   // JAM
+    cpu.step();
+    cpu.mem_mut().nmi = true;
+    cpu.step();
+    cpu.reset();
+    let pc = cpu.pc();
+    cpu.step();
+    assert!(cpu.pc() == pc + 1);
+}
+
+struct IrqCard;
+
+impl Peripheral for IrqCard {
+    fn doIO(&mut self, _: u16, val: u8) -> u8     { val }
+    fn doHighIO(&mut self, _: u16, val: u8) -> u8 { val }
+    fn irq(&mut self) -> bool                     { true }
+}
+
+#[test]
+fn test_peripheral_irq()
+{
+    let mut a2 = AppleII::new();
+    assert!(!a2.irq_line() && !a2.nmi_line());
+    a2.set_slot(4, ~IrqCard);
+    assert!(a2.irq_line() && !a2.nmi_line());
+}
+
+#[test]
+fn test_reset_sequence()
+{
+    let mut cpu = run_program([]);
+    assert!(cpu.s() == 0xfd && cpu.p() == 0x24 && cpu.cycles() == 7);
+    // Nothing was pushed
+    assert!(cpu.mem.mem[0x1fb] == 0 && cpu.mem.mem[0x1fc] == 0 && cpu.mem.mem[0x1fd] == 0);
+    // A warm reset keeps going down the stack, and sets I but leaves the other flags.
+    cpu.set_p(0xc3);
+    cpu.reset();
+    assert!(cpu.s() == 0xfa && cpu.p() == 0xe7 && cpu.pc() == 0x0200);
+}
+
+#[test]
+fn test_pushed_status()
+{
+    // PHP; PLA; BRK, with the BRK handler at $0300
+    let mut cpu = run_program([]);
+    let prog = [0x08, 0x68, 0x00];
+    for i in range(0, prog.len()) {
+        cpu.mem.mem[0x200 + i] = prog[i];
+    }
+    cpu.mem.mem[0x7fe] = 0x00;
+    cpu.mem.mem[0x7ff] = 0x03;
+    cpu.set_p(0x00);
+    cpu.step();
+    cpu.step();
+    // PHP pushes B and the unused bit
+    assert!(cpu.a() == 0x30);
+    cpu.step();
+    assert!(cpu.pc() == 0x0300 && cpu.mem.mem[0x1fb] == 0x30);
+    // BRK skips its signature byte
+    assert!(cpu.mem.mem[0x1fc] == 0x04 && cpu.mem.mem[0x1fd] == 0x02);
+    // RTI, like PLP, ignores B and the unused bit.
+    cpu.mem.mem[0x300] = 0x40;
+    cpu.mem.mem[0x1fb] = 0xdf;
+    cpu.step();
+    assert!(cpu.pc() == 0x0204 && cpu.p() == 0xef && cpu.s() == 0xfd);
+}
+
+#[test]
+fn test_stack_wraps()
+{
+    // JSR $0300 with S = $00, then RTS
+    let mut cpu = run_program([]);
+    let prog = [0x20, 0x00, 0x03];
+    for i in range(0, prog.len()) {
+        cpu.mem.mem[0x200 + i] = prog[i];
+    }
+    cpu.mem.mem[0x300] = 0x60;
+    cpu.set_pc(0x0200);
+    cpu.set_s(0x00);
+    cpu.step();
+    assert!(cpu.s() == 0xfe && cpu.mem.mem[0x100] == 0x02 && cpu.mem.mem[0x1ff] == 0x02);
+    cpu.step();
+    assert!(cpu.s() == 0x00 && cpu.pc() == 0x0203);
+}
+
+// Flat 64K of RAM that notes which cycle each write happened on.
+pub struct CycleRam { mem: ~[u8], writes: ~[(u16, u64)] }
+
+impl Mem for CycleRam {
+    fn loadb(&mut self, addr: u16) -> u8     { self.mem[addr] }
+    fn storeb(&mut self, addr: u16, val: u8) { self.mem[addr] = val }
+    fn storeb_cy(&mut self, addr: u16, val: u8, cy: u64) {
+        self.writes.push((addr, cy));
+        self.storeb(addr, val)
+    }
+}
+
+#[test]
+fn test_cycle_accurate_writes()
+{
+    // STA $0400; INC $0400; LDA $02FF,X with X = 1
+    let mut ram = CycleRam { mem: vec::from_elem(0x10000, 0u8), writes: ~[] };
+    let prog = [0x8d, 0x00, 0x04, 0xee, 0x00, 0x04, 0xbd, 0xff, 0x02];
+    for i in range(0, prog.len()) {
+        ram.mem[0x200 + i] = prog[i];
+    }
+    let mut cpu = Cpu::new(ram);
+    cpu.set_pc(0x0200);
+    cpu.set_x(0x01);
+    cpu.set_cycle_accurate(true);
+    cpu.step();
+    cpu.step();
+    // The store is on the last of its 4 cycles, and INC writes on both of its last two.
+    assert!(cpu.mem().writes == ~[(0x0400, 3), (0x0400, 8), (0x0400, 9)]);
+    cpu.step();
+    assert!(cpu.cycles() == 4 + 6 + 5);
+
+    // The same program all at once
+    cpu.mem_mut().writes = ~[];
+    cpu.set_cycle_accurate(false);
+    cpu.set_pc(0x0200);
+    cpu.step();
+    cpu.step();
+    cpu.step();
+    assert!(cpu.mem().writes.len() == 0);
+    assert!(cpu.cycles() == 2 * (4 + 6 + 5));
+}
+
+// Counts its I/O accesses, and returns the count when read.
+struct CountingCard { count: u8 }
+
+impl Peripheral for CountingCard {
+    fn doIO(&mut self, _: u16, _: u8) -> u8       { self.count += 1; self.count }
+    fn doHighIO(&mut self, _: u16, val: u8) -> u8 { val }
+}
+
+// Runs a program at $0300 on an Apple II with a CountingCard in slot 1 ($C090-$C09F), and
+// returns A.
+fn run_soft_switch_program(variant: CpuVariant, prog: &[u8]) -> u8
+{
+    let mut a2 = AppleII::new();
+    a2.set_slot(1, ~CountingCard { count: 0 });
+    for i in range(0, prog.len()) {
+        a2.storeb(0x300 + i as u16, prog[i]);
+    }
+    let mut cpu = Cpu::with_variant(a2, variant);
+    cpu.set_pc(0x0300);
+    while cpu.pc() < 0x300 + prog.len() as u16 {
+        cpu.step();
+    }
+    cpu.a()
+}
+
+#[test]
+fn test_soft_switch_dummy_accesses()
+{
+    // INC $C090; STA $C090,X; LDA $C090
+    let prog = [0xee, 0x90, 0xc0, 0x9d, 0x90, 0xc0, 0xad, 0x90, 0xc0];
+    // INC reads, writes back and writes; STA abs,X reads the (unchanged) address first.
+    assert!(run_soft_switch_program(Nmos6502, prog) == 3 + 2 + 1);
+    // The 65C02's INC reads twice instead, and its STA re-reads its own operand.
+    assert!(run_soft_switch_program(Cmos65C02, prog) == 3 + 1 + 1);
+    // LDA $C08F,Y with Y = 1 doesn't cross a page, so it reads just once.
+    assert!(run_soft_switch_program(Nmos6502, [0xa0, 0x01, 0xb9, 0x8f, 0xc0]) == 1);
+}
+
+#[test]
+fn test_run_loops()
+{
+    // NOPs from $0200
+    let mut cpu = line_ram_cpu();
+    assert!(cpu.run_until_pc(0x0210, 1000) == ReachedPc);
+    assert!(cpu.pc() == 0x0210 && cpu.cycles() == 32);
+    assert!(cpu.run_until_pc(0x0210, 1000) == ReachedPc);
+    assert!(cpu.cycles() == 32);
+    // Budgets are rounded up to a whole instruction.
+    assert!(cpu.run_cycles(9) == RanCycles);
+    assert!(cpu.cycles() == 42);
+    assert!(cpu.run_until_pc(0x0100, 10) == RanCycles);
+
+    // INC $10; JMP $0200
+    let mut cpu = line_ram_cpu();
+    let prog = [0xe6, 0x10, 0x4c, 0x00, 0x02];
+    for i in range(0, prog.len()) {
+        cpu.mem_mut().mem[0x200 + i] = prog[i];
+    }
+    cpu.mem_mut().mem[0x10] = 0;
+    assert!(cpu.run_until(1000, |cpu| cpu.mem().mem[0x10] == 5) == ConditionMet);
+    assert!(cpu.pc() == 0x0202 && cpu.cycles() == 4 * 8 + 5);
+
+    cpu.mem_mut().mem[0x0202] = 0x02;
+    assert!(cpu.run_cycles(1000) == Halted(Jammed(0x02)));
+}
+
+#[test]
+fn test_run_frame()
+{
+    let mut cpu = Cpu::new(AppleII::new());
+    cpu.set_cycles(100);
+    assert!(cpu.run_frame() == RanCycles);
+    // Frames end on fixed boundaries, so overrunning one doesn't push the next one back.
+    let cy = cpu.cycles();
+    assert!(cy >= CYCLES_PER_FRAME && cy < CYCLES_PER_FRAME + 7);
+    cpu.run_frame();
+    let cy = cpu.cycles();
+    assert!(cy >= 2 * CYCLES_PER_FRAME && cy < 2 * CYCLES_PER_FRAME + 7);
+}
+
+// An Apple II with $D0 and $E0 in ROM at $D000 and $E000
+fn lc_machine() -> AppleII
+{
+    let mut a2 = AppleII::new();
+    a2.mem[0xd000] = 0xd0;
+    a2.mem[0xe000] = 0xe0;
+    a2
+}
+
+// Writes $11 to $D000 and $22 to $E000, returning whether each went to RAM: bank 1, bank 2
+// and $E000
+fn lc_writes(a2: &mut AppleII) -> (bool, bool, bool)
+{
+    a2.storeb(0xd000, 0x11);
+    a2.storeb(0xe000, 0x22);
+    // the ROM's untouched either way
+    assert!(a2.mem[0xd000] == 0xd0 && a2.mem[0xe000] == 0xe0);
+    // bank 1's $D000 is kept at $10000, bank 2's at $C000 and $E000 at $11000
+    (a2.mem[0x10000] == 0x11, a2.mem[0xc000] == 0x11, a2.mem[0x11000] == 0x22)
+}
+
+#[test]
+fn test_language_card_switches()
+{
+    for n in range(0u16, 16) {
+        let switch = 0xc080 + n;
+        let bank1 = n & 8 != 0;
+        let readram = n & 3 == 0 || n & 3 == 3;
+        let writeram = n & 1 != 0;
+
+        // One read only write enables if it's the second from an odd switch
+        let mut a2 = lc_machine();
+        a2.loadb(switch);
+        assert!(lc_writes(&mut a2) == (false, false, false));
+        let expected = if readram { 0 } else { 0xd0 };
+        assert!(a2.loadb(0xd000) == expected);
+
+        let mut a2 = lc_machine();
+        a2.loadb(switch);
+        a2.loadb(switch);
+        let written = lc_writes(&mut a2);
+        assert!(written == (writeram && bank1, writeram && !bank1, writeram));
+        let expected = if !readram { 0xd0 } else if writeram { 0x11 } else { 0 };
+        assert!(a2.loadb(0xd000) == expected);
+        assert!(a2.loadb(0xe000) == if !readram { 0xe0 } else if writeram { 0x22 } else { 0 });
+
+        // Writes select the bank and what's read, but don't write enable
+        let mut a2 = lc_machine();
+        a2.storeb(switch, 0);
+        a2.storeb(switch, 0);
+        assert!(lc_writes(&mut a2) == (false, false, false));
+        assert!(a2.loadb(0xd000) == if readram { 0 } else { 0xd0 });
+    }
+}
+
+#[test]
+fn test_language_card_prewrite()
+{
+    // Any two odd switches in a row write enable: here the first sets bank 2, the second bank 1
+    let mut a2 = lc_machine();
+    a2.loadb(0xc081);
+    a2.loadb(0xc089);
+    assert!(lc_writes(&mut a2) == (true, false, true));
+
+    // A write to an odd switch resets the pre-write latch, so this is one read again
+    let mut a2 = lc_machine();
+    a2.loadb(0xc08b);
+    a2.storeb(0xc08b, 0);
+    a2.loadb(0xc08b);
+    assert!(lc_writes(&mut a2) == (false, false, false));
+    // but doesn't write protect the RAM once it's enabled
+    a2.loadb(0xc08b);
+    a2.storeb(0xc08b, 0);
+    assert!(lc_writes(&mut a2) == (true, false, true));
+
+    // An even switch, read or written, write protects it and resets the latch
+    a2.storeb(0xc08a, 0);
+    a2.loadb(0xc08b);
+    a2.mem[0x10000] = 0;
+    a2.mem[0x11000] = 0;
+    assert!(lc_writes(&mut a2) == (false, false, false));
+    assert!(a2.status()[1] == ~"language card: read RAM, write off, bank 1, pre-write");
+}
+
+// Counts writes to its $Cn00 ROM space.
+struct HighWriteCard { writes: uint }
+
+impl Peripheral for HighWriteCard {
+    fn doIO(&mut self, _: u16, val: u8) -> u8     { val }
+    fn doHighIO(&mut self, _: u16, val: u8) -> u8 { self.writes += 1; val }
+    fn status(&self) -> ~str                     { format!("writes: {}", self.writes) }
+}
+
+#[test]
+fn test_rom_writes_go_nowhere()
+{
+    // $D600 once reached slot 6's ROM space when the language card was write protected
+    let mut a2 = AppleII::new();
+    a2.set_slot(6, ~HighWriteCard { writes: 0 });
+    a2.storeb(0xd600, 0x12);
+    a2.storeb(0xc600, 0x12);
+    assert!(a2.status()[2] == ~"slot 6: writes: 1");
+}
+
+// A IIe, with each page of its ROM filled with its page number
+fn iie_memory() -> AppleII
+{
+    let mut a2 = AppleII::with_model(ModelIIe);
+    let rom: ~[u8] = range(0u, 0x4000).map(|i| (i >> 8) as u8).collect();
+    a2.load_rom(rom);
+    a2
+}
+
+#[test]
+fn test_models()
+{
+    let models = [ModelII, ModelIIPlus, ModelIIe, ModelIIeEnhanced];
+    for (i, model) in models.iter().enumerate() {
+        assert!(models.iter().position(|m| m.name() == model.name()) == Some(i));
+        assert!(AppleII::with_model(*model).model_name() == model.name());
+    }
+    assert!(from_str::<Model>("enhanced-iie") == Some(ModelIIeEnhanced));
+    assert!(from_str::<Model>("iic") == None);
+    assert!(ModelIIeEnhanced.cpu_variant() == Cmos65C02 && ModelIIe.cpu_variant() == Nmos6502);
+    assert!(!AppleII::new().has_aux_memory() && AppleII::with_model(ModelIIe).has_aux_memory());
+
+    // Lowercase came with the IIe
+    let mut a2 = AppleII::new();
+    a2.keyPressed('a' as u8);
+    assert!(a2.loadb(0xc000) == 'A' as u8 | 0x80);
+    let mut a2 = AppleII::with_model(ModelIIeEnhanced);
+    a2.keyPressed('a' as u8);
+    assert!(a2.loadb(0xc000) == 'a' as u8 | 0x80);
+
+    // The II+'s 12K ROM starts at $D000, the IIe's 16K at $C000
+    let mut a2 = AppleII::with_model(ModelII);
+    let rom: ~[u8] = range(0u, 0x3000).map(|i| (i >> 8) as u8).collect();
+    a2.load_rom(rom);
+    assert!(a2.loadb(0xd000) == 0x00 && a2.loadb(0xffff) == 0x2f);
+    let mut a2 = iie_memory();
+    assert!(a2.loadb(0xd000) == 0x10 && a2.loadb(0xffff) == 0x3f);
+}
+
+#[test]
+fn test_iie_aux_memory()
+{
+    let mut a2 = iie_memory();
+    a2.storeb(0x1000, 0x11);
+    a2.storeb(0x0080, 0x01);
+    // RAMWRT and RAMRD are separate
+    a2.storeb(0xc005, 0);
+    a2.storeb(0x1000, 0x22);
+    assert!(a2.loadb(0x1000) == 0x11);
+    a2.storeb(0xc003, 0);
+    assert!(a2.loadb(0x1000) == 0x22);
+    assert!(a2.loadb(0xc013) & 0x80 != 0 && a2.loadb(0xc014) & 0x80 != 0);
+    // but don't move zero page, which ALTZP does
+    assert!(a2.loadb(0x0080) == 0x01 && a2.loadb(0xc016) & 0x80 == 0);
+    a2.storeb(0xc009, 0);
+    a2.storeb(0x0080, 0x02);
+    assert!(a2.loadb(0x0080) == 0x02);
+    a2.storeb(0xc008, 0);
+    a2.storeb(0xc002, 0);
+    a2.storeb(0xc004, 0);
+    assert!(a2.loadb(0x0080) == 0x01 && a2.loadb(0x1000) == 0x11);
+    assert!(a2.loadb(0xc013) & 0x80 == 0 && a2.loadb(0xc014) & 0x80 == 0);
+
+    // With 80STORE, PAGE2 picks the bank for the text page instead of the page shown
+    a2.storeb(0xc001, 0);
+    a2.loadb(0xc055);
+    a2.storeb(0x0400, 0x33);
+    assert!(!a2.display_page2() && a2.loadb(0xc018) & 0x80 != 0);
+    a2.loadb(0xc054);
+    assert!(a2.loadb(0x0400) == 0);
+    assert!(a2.aux_mem().unwrap()[0x0400] == 0x33);
+
+    // The language card's RAM is switched by ALTZP too; $C08B twice is bank 1, read and write
+    a2.loadb(0xc08b);
+    a2.loadb(0xc08b);
+    a2.storeb(0xc009, 0);
+    a2.storeb(0xd000, 0x44);
+    assert!(a2.loadb(0xd000) == 0x44);
+    a2.storeb(0xc008, 0);
+    assert!(a2.loadb(0xd000) == 0);
+
+    // Neither memory nor switches change on a II+
+    let mut a2 = AppleII::new();
+    a2.storeb(0xc005, 0);
+    a2.storeb(0x1000, 0x22);
+    assert!(a2.loadb(0x1000) == 0x22 && a2.aux_mem().is_none());
+}
+
+#[test]
+fn test_iie_internal_rom()
+{
+    let mut a2 = iie_memory();
+    // Slot 3 is the internal 80-column firmware unless SLOTC3ROM is on, and using it maps in
+    // the internal $C800-$CFFF until $CFFF is accessed
+    assert!(a2.loadb(0xc300) == 0x03 && a2.loadb(0xc017) & 0x80 == 0);
+    assert!(a2.loadb(0xc800) == 0x08 && a2.loadb(0xcfff) == 0x0f);
+    a2.storeb(0xc00b, 0);
+    assert!(a2.loadb(0xc017) & 0x80 != 0);
+    // INTCXROM maps in all of it
+    a2.storeb(0xc007, 0);
+    assert!(a2.loadb(0xc600) == 0x06 && a2.loadb(0xc015) & 0x80 != 0);
+
+    // The IIe's switches and aux memory are in snapshots
+    a2.storeb(0xc005, 0);
+    a2.storeb(0x1000, 0x55);
+    let mut cpu = Cpu::new(a2);
+    let saved = save_to_vec(&cpu);
+    let mut other = Cpu::new(iie_memory());
+    assert!(load_from_slice(&mut other, saved.as_slice()).is_ok());
+    assert!(other.mem().aux_mem().unwrap()[0x1000] == 0x55);
+    assert!(other.mem().status() == cpu.mem().status());
+    assert!(other.mem_mut().loadb(0xc600) == 0x06);
+    assert!(cpu.mem_mut().loadb(0xc600) == 0x06);
+}
+
+#[test]
+fn test_peek()
+{
+    // Peeking leaves the keyboard strobe, the soft switches and the $C800 ROM selection alone
+    let mut a2 = iie_memory();
+    a2.keyPressed(0x41);
+    let status = a2.status();
+    a2.peekb(0xc010);
+    a2.peekb(0xc055);
+    a2.peekb(0xc081);
+    a2.peekb(0xc081);
+    assert!(a2.peekb(0xc000) == 0xc1 && a2.status() == status);
+    assert!(a2.peekb(0xc300) == 0x03 && a2.peekb(0xc800) == 0);
+    // but sees what the CPU would
+    a2.loadb(0xc300);
+    assert!(a2.peekb(0xc800) == 0x08 && a2.peekb(0xfffc) == a2.loadb(0xfffc));
+}
+
+#[test]
+fn test_state_api()
+{
+    // LDA $10,X
+    let mut cpu = run_program([0xb5, 0x10]);
+    let mut regs = cpu.regs();
+    regs.x = 0x05;
+    regs.pc = 0x0200;
+    cpu.set_regs(regs.clone());
+    cpu.mem_mut().mem[0x15] = 0x80;
+    cpu.set_cycles(100);
+    cpu.step();
+    assert!(cpu.a() == 0x80 && cpu.x() == 0x05 && cpu.pc() == 0x0202);
+    assert!((cpu.p() & 0x82) == 0x80);
+    assert!(cpu.cycles() == 104);
+    assert!(cpu.regs() != regs);
+
+    // Bit 5 always reads back as set, and B as clear.
+    cpu.set_p(0xdf);
+    assert!(cpu.p() == 0xef);
+    assert!(cpu.mem().mem[0x15] == 0x80);
+}
+
+// A Debugger on a LineRam CPU, with a program at $0200.
+fn debugger_with(prog: &[u8]) -> Debugger<LineRam>
+{
+    let mut cpu = line_ram_cpu();
+    for i in range(0, prog.len()) {
+        cpu.mem_mut().mem[0x200 + i] = prog[i];
+    }
+    Debugger::new(cpu)
+}
+
+#[test]
+fn test_debugger_breakpoints()
+{
+    // INX; JMP $0200
+    let mut dbg = debugger_with([0xe8, 0x4c, 0x00, 0x02]);
+    let id = dbg.add_breakpoint(0x0200, RegisterIs(RegX, 3));
+    assert!(dbg.go(1000) == HitBreakpoint(id));
+    assert!(dbg.cpu().x() == 3 && dbg.cpu().pc() == 0x0200);
+    // Going again starts by running the instruction at the breakpoint, so X has to wrap around.
+    assert!(dbg.go(10000) == HitBreakpoint(id));
+    assert!(dbg.cpu().x() == 3);
+    assert!(dbg.hits(id) == Some(2));
+
+    let always = dbg.add_breakpoint(0x0201, Always);
+    assert!(dbg.go(1000) == HitBreakpoint(always));
+    assert!(dbg.remove(always) && dbg.remove(id));
+    assert!(!dbg.remove(id));
+    assert!(dbg.go(100) == OutOfCycles);
+}
+
+#[test]
+fn test_debugger_watchpoints()
+{
+    // LDA #$5A; STA $11; BIT $C030
+    let mut dbg = debugger_with([0xa9, 0x5a, 0x85, 0x11, 0x2c, 0x30, 0xc0]);
+    let id = dbg.add_watchpoint(0x0010, 0x001f, WatchWrite);
+    let speaker = dbg.add_soft_switch_breakpoint(0xc030);
+    assert!(dbg.go(1000) == HitWatchpoint(id, BusAccess { addr: 0x11, val: 0x5a, write: true }));
+    assert!(dbg.cpu().pc() == 0x0204);
+    match dbg.go(1000) {
+        HitWatchpoint(hit, access) => assert!(hit == speaker && !access.write),
+        _ => fail!("expected the soft switch breakpoint")
+    }
+    assert!(dbg.cpu().pc() == 0x0207);
+    assert!(dbg.hits(id) == Some(1) && dbg.hits(speaker) == Some(1));
+}
+
+#[test]
+fn test_debugger_stepping()
+{
+    // JSR $0300; NOP, with NOP; NOP; RTS at $0300
+    let mut dbg = debugger_with([0x20, 0x00, 0x03, 0xea]);
+    let sub = [0xea, 0xea, 0x60];
+    for i in range(0, sub.len()) {
+        dbg.cpu_mut().mem_mut().mem[0x300 + i] = sub[i];
+    }
+    assert!(dbg.step_over(1000) == StepDone);
+    assert!(dbg.cpu().pc() == 0x0203 && dbg.cpu().cycles() == 6 + 2 + 2 + 6);
+    assert!(dbg.step_over(1000) == StepDone);
+    assert!(dbg.cpu().pc() == 0x0204);
+
+    dbg.cpu_mut().set_pc(0x0200);
+    assert!(dbg.step_into() == StepDone);
+    assert!(dbg.cpu().pc() == 0x0300);
+    assert!(dbg.step_into() == StepDone);
+    assert!(dbg.step_out(1000) == StepDone);
+    assert!(dbg.cpu().pc() == 0x0203 && dbg.cpu().s() == 0xfd);
+}
+
+fn printed(reply: MonitorReply) -> ~[~str]
+{
+    match reply {
+        Print(lines) => lines,
+        _ => fail!("expected the monitor to print")
+    }
+}
+
+#[test]
+fn test_monitor()
+{
+    let mut dbg = Debugger::new(Cpu::new(AppleII::new()));
+    let mut monitor = Monitor::new();
+    // LDA #$05; NOP; RTS
+    assert!(printed(monitor.execute(&mut dbg, "300:A9 05 EA 60")).len() == 0);
+    assert!(printed(monitor.execute(&mut dbg, "300.303")) == ~[~"0300- A9 05 EA 60"]);
+    assert!(printed(monitor.execute(&mut dbg, "301")) == ~[~"0301- 05"]);
+    let listing = printed(monitor.execute(&mut dbg, "300l"));
+    assert!(listing[0] == ~"0300  A9 05     LDA #$05" && listing[1] == ~"0302  EA        NOP");
+
+    assert!(printed(monitor.execute(&mut dbg, "302P")) == ~[~"BREAKPOINT 1 AT 0302"]);
+    match monitor.execute(&mut dbg, "300G") { Go => {}, _ => fail!("expected to go") }
+    assert!(dbg.go(100) == HitBreakpoint(1));
+    let regs = printed(monitor.execute(&mut dbg, "R"));
+    assert!(regs[0].starts_with("A=05") && regs[0].ends_with("PC=0302"));
+    assert!(printed(monitor.execute(&mut dbg, "P")) == ~[~"1: 0302 HITS 1"]);
+    monitor.execute(&mut dbg, "K");
+    assert!(printed(monitor.execute(&mut dbg, "P")).len() == 0);
+
+    assert!(printed(monitor.execute(&mut dbg, "300X")) == ~[~"SYNTAX ERROR"]);
+    assert!(printed(monitor.execute(&mut dbg, "300.2FF")) == ~[~"BAD RANGE"]);
+    assert!(printed(monitor.execute(&mut dbg, "300:1FF")) == ~[~"BAD BYTE"]);
+
+    // A movie being recorded couldn't play back changes made from the monitor
+    monitor.set_recording(true);
+    for cmd in ["300:EA", "300G", "U", "300U", "LOAD saved"].iter() {
+        assert!(printed(monitor.execute(&mut dbg, *cmd)) == ~[~"NOT WHILE RECORDING"]);
+    }
+    assert!(printed(monitor.execute(&mut dbg, "300")) == ~[~"0300- A9"]);
+    let status = dbg.cpu().mem().status();
+    monitor.execute(&mut dbg, "C050.C08F");
+    monitor.execute(&mut dbg, "C050L");
+    assert!(dbg.cpu().mem().status() == status);
+    match monitor.execute(&mut dbg, "Q") { Quit => {}, _ => fail!("expected to quit") }
+}
+
+// The client end of a GDB connection: what it sends is scripted, and what it receives recorded.
+struct ScriptedClient { input: MemReader, output: MemWriter }
+
+impl Reader for ScriptedClient {
+    fn read(&mut self, buf: &mut [u8]) -> Option<uint> { self.input.read(buf) }
+}
+
+impl Writer for ScriptedClient {
+    fn write(&mut self, buf: &[u8]) { self.output.write(buf) }
+}
+
+fn gdb_packet(data: &str) -> ~str
+{
+    let sum = data.bytes().fold(0u8, |sum, b| sum + b);
+    format!("${}\\#{:02x}", data, sum)
+}
+
+#[test]
+fn test_gdb_stub()
+{
+    // LDA #$42; TAX; INX
+    let mut dbg = debugger_with([0xa9, 0x42, 0xaa, 0xe8]);
+    // Each pair is a packet and the stub's reply
+    let script = [
+        ("?", "S05"),
+        ("qSupported:multiprocess+", "PacketSize=1000"),
+        ("g", "000000fd240002"),
+        ("m200,4", "a942aae8"),
+        ("Z0,203,1", "OK"),
+        ("c", "S05"),
+        ("p5", "0302"),
+        ("p1", "42"),
+        ("s", "S05"),
+        ("P0=10", "OK"),
+        ("g", "104300fd240402"),
+        ("z0,203,1", "OK"),
+        ("z0,203,1", "E01"),
+        // STA $10
+        ("M204,2:8510", "OK"),
+        ("Z2,10,1", "OK"),
+        ("c", "T05watch:0010;"),
+        ("vMustReplyEmpty", ""),
+        ("D", "OK"),
+    ];
+    // A packet with a bad checksum is refused, and GDB's acks are skipped
+    let mut input = ~"$g#00";
+    let mut expected = ~"-";
+    for &(packet, reply) in script.iter() {
+        input.push_str("+");
+        input.push_str(gdb_packet(packet));
+        expected.push_str("+");
+        expected.push_str(gdb_packet(reply));
+    }
+    let mut client = ScriptedClient { input: MemReader::new(input.into_bytes()),
+                                      output: MemWriter::new() };
+    GdbStub::new(1000).serve(&mut dbg, &mut client);
+    assert!(str::from_utf8(client.output.inner_ref().as_slice()) == expected.as_slice());
+    assert!(dbg.cpu().mem().mem[0x10] == 0x10);
+    assert!(dbg.breakpoints().len() == 0 && dbg.watchpoints().len() == 0);
+
+    // A read too long for a packet is cut short: the ack, then $, 498 bytes in hex, # and the
+    // checksum
+    let mut client = ScriptedClient { input: MemReader::new(gdb_packet("m0,1000").into_bytes()),
+                                      output: MemWriter::new() };
+    GdbStub::new(1000).serve(&mut dbg, &mut client);
+    assert!(client.output.inner_ref().len() == 1 + 1 + 498 * 2 + 3);
+
+    // and reading the soft switches doesn't flip them
+    let mut dbg = Debugger::new(Cpu::new(AppleII::new()));
+    let status = dbg.cpu().mem().status();
+    let mut client = ScriptedClient { input: MemReader::new(gdb_packet("mc050,40").into_bytes()),
+                                      output: MemWriter::new() };
+    GdbStub::new(1000).serve(&mut dbg, &mut client);
+    assert!(dbg.cpu().mem().status() == status);
+}
+
+#[test]
+fn test_trace()
+{
+    // LDA #$42; STA $C030; INX
+    let prog = [0xa9, 0x42, 0x8d, 0x30, 0xc0, 0xe8];
+    let mut cpu = line_ram_cpu();
+    for i in range(0, prog.len()) {
+        cpu.mem_mut().mem[0x200 + i] = prog[i];
+    }
+    cpu.set_tracer(Some(Tracer::new(2)));
+    for _ in range(0, 3) {
+        cpu.step();
+    }
+    // The ring buffer only keeps the last two
+    let records = cpu.tracer().unwrap().records();
+    assert!(records.len() == 2);
+    let sta = records[0].clone();
+    assert!(sta.regs.pc == 0x0202 && sta.cy == 2 && sta.opcode() == 0x8d);
+    assert!(sta.accesses == ~[BusAccess { addr: 0x0202, val: 0x8d, write: false },
+                              BusAccess { addr: 0x0203, val: 0x30, write: false },
+                              BusAccess { addr: 0x0204, val: 0xc0, write: false },
+                              BusAccess { addr: 0xc030, val: 0x42, write: true }]);
+    assert!(sta.to_text(Nmos6502) ==
+            ~"0202  8D 30 C0  STA $C030      A:42 X:00 Y:00 P:24 SP:FD CYC:2");
+
+    // Only the soft switch access gets through the filter
+    cpu.set_pc(0x0200);
+    let mut tracer = Tracer::new(10);
+    tracer.set_filter(TraceFilter { pc_lo: 0, pc_hi: 0xffff, soft_switches: true });
+    assert!(cpu.set_tracer(Some(tracer)).is_some());
+    for _ in range(0, 3) {
+        cpu.step();
+    }
+    assert!(cpu.tracer().unwrap().records().len() == 1);
+
+    // The binary log round trip
+    let mut log = MemWriter::new();
+    log.write(TRACE_MAGIC);
+    log.write_u8(TRACE_VERSION);
+    for record in records.iter() {
+        record.write_to(&mut log);
+    }
+    let mut reader = MemReader::new(log.inner());
+    assert!(read_log(&mut reader) == records);
+}
+
+#[test]
+fn test_snapshot()
+{
+    let mut cpu = Cpu::new(AppleII::new());
+    cpu.mem_mut().storeb(0x0300, 0x42);
+    cpu.mem_mut().loadb(0xc050);            // graphics
+    cpu.mem_mut().loadb(0xc08b);            // language card RAM, bank 1
+    cpu.set_a(0x12);
+    cpu.set_cycles(1000);
+    let saved = save_to_vec(&cpu);
+
+    let mut other = Cpu::new(AppleII::new());
+    assert!(load_from_slice(&mut other, saved.as_slice()).is_ok());
+    assert!(other.a() == 0x12 && other.cycles() == 1000);
+    assert!(other.mem_mut().loadb(0x0300) == 0x42);
+    assert!(other.mem().status() == cpu.mem().status());
+    assert!(save_to_vec(&other) == saved);
+}
+
+#[test]
+fn test_save_file()
+{
+    let mut cpu = Cpu::new(AppleII::new());
+    let model = cpu.mem().model_name();
+    cpu.mem_mut().storeb(0x0300, 0x42);
+    cpu.set_pc(0x0300);
+    let mut file = MemWriter::new();
+    write_save_file(&mut file, model, &cpu);
+    let mut saved = file.inner();
+    // A chunk from some later version, which loading skips
+    let mut extra = StateWriter::new();
+    extra.chunk("NEW!", 1, |w| w.write([1u8, 2, 3]));
+    saved.push_all(extra.finish());
+
+    let mut other = Cpu::new(AppleII::new());
+    assert!(read_save_file(&mut MemReader::new(saved.clone()), model, &mut other).is_ok());
+    assert!(other.pc() == 0x0300 && other.mem_mut().loadb(0x0300) == 0x42);
+
+    // Neither a save file for another model nor something else entirely loads
+    assert!(read_save_file(&mut MemReader::new(saved.clone()), "Apple //e", &mut other).is_err());
+    assert!(read_save_file(&mut MemReader::new(~[0u8, ..16]), model, &mut other).is_err());
+
+    // A damaged one is an error, and changes nothing: not one cut short, nor one for a machine
+    // with other cards or memory
+    let before = save_to_vec(&other);
+    for len in [3u, 12, 200, saved.len() - 20].iter() {
+        let cut = saved.slice_to(*len).to_owned();
+        assert!(read_save_file(&mut MemReader::new(cut), model, &mut other).is_err());
+    }
+    let mut with_card = Cpu::new(AppleII::new());
+    with_card.mem_mut().set_slot(6, ~HighWriteCard { writes: 0 });
+    assert!(load_from_slice(&mut with_card, save_to_vec(&cpu).as_slice()).is_err());
+    let iie = Cpu::new(iie_memory());
+    assert!(load_from_slice(&mut other, save_to_vec(&iie).as_slice()).is_err());
+    assert!(save_to_vec(&other) == before);
+}
+
+#[test]
+fn test_rewind()
+{
+    // INX, over and over
+    let mut dbg = debugger_with([0xe8u8, ..16]);
+    // Going back does nothing until rewind is enabled
+    dbg.step_into();
+    assert!(!dbg.step_back() && dbg.cpu().x() == 1);
+
+    dbg.enable_rewind(5, 10);
+    for _ in range(0, 7) {
+        dbg.step_into();
+    }
+    assert!(dbg.cpu().x() == 8 && dbg.cpu().pc() == 0x0208);
+    assert!(dbg.step_back());
+    assert!(dbg.cpu().x() == 7 && dbg.cpu().pc() == 0x0207 && dbg.cpu().cycles() == 14);
+    assert!(dbg.run_back_to(0x0203));
+    assert!(dbg.cpu().x() == 3 && dbg.cpu().cycles() == 6);
+
+    // The oldest snapshot is from when rewind was enabled, after the first INX
+    assert!(!dbg.run_back_to(0x0200));
+    assert!(dbg.cpu().x() == 3 && dbg.cpu().pc() == 0x0203);
+    assert!(dbg.step_back() && dbg.step_back());
+    assert!(dbg.cpu().x() == 1);
+    assert!(!dbg.step_back());
+
+    // Running forward again after going back
+    dbg.step_into();
+    assert!(dbg.cpu().x() == 2 && dbg.cpu().pc() == 0x0202);
+
+    // Replaying doesn't trace again what was traced the first time through
+    let mut cpu = line_ram_cpu();
+    let mut rewind = Rewind::new(4, 10);
+    cpu.set_tracer(Some(Tracer::new(100)));
+    for _ in range(0, 6) {
+        rewind.snapshot(&cpu);
+        cpu.step();
+    }
+    assert!(rewind.step_back(&mut cpu) && rewind.run_back_to(&mut cpu, 0x0202));
+    assert!(cpu.pc() == 0x0202);
+    let records = cpu.tracer().unwrap().records();
+    assert!(records.len() == 6);
+    assert!(records.iter().zip(records.iter().skip(1)).all(|(a, b)| a.cy < b.cy));
+}
+
+#[test]
+fn test_movie()
+{
+    // Wait for a key, then store it and a byte of noise from the button inputs, and go again
+    let program = [0xadu8, 0x00, 0xc0,     // LDA $C000
+                   0x10, 0xfb,             // BPL $0300
+                   0x8d, 0x10, 0xc0,       // STA $C010
+                   0xe8,                   // INX
+                   0x9d, 0x00, 0x10,       // STA $1000,X
+                   0xad, 0x61, 0xc0,       // LDA $C061
+                   0x9d, 0x00, 0x20,       // STA $2000,X
+                   0x4c, 0x00, 0x03];      // JMP $0300
+    let mut cpu = Cpu::new(AppleII::new());
+    for (i, b) in program.iter().enumerate() {
+        cpu.mem_mut().storeb(0x0300 + i as u16, *b);
+    }
+    cpu.set_pc(0x0300);
+    let model = cpu.mem().model_name();
+
+    let mut movie = Movie::record(&cpu);
+    for key in "HELLO".bytes() {
+        cpu.run_cycles(1000);
+        movie.input(&mut cpu, KeyPress(key));
+    }
+    cpu.run_cycles(1000);
+    assert!(cpu.x() == 5 && cpu.mem_mut().loadb(0x1005) == 'O' as u8 | 0x80);
+
+    let mut file = MemWriter::new();
+    movie.write_to(&mut file, model);
+    let saved = file.inner();
+    let movie = Movie::read_from(&mut MemReader::new(saved.clone()), model).unwrap();
+    assert!(movie.events.len() == 5 && movie.events[0] == (1001, KeyPress('H' as u8)));
+
+    // One cut short anywhere is an error, not a crash
+    for len in [0u, 5, 12, 100, saved.len() - 1].iter() {
+        let cut = saved.slice_to(*len).to_owned();
+        assert!(Movie::read_from(&mut MemReader::new(cut), model).is_err());
+    }
+
+    // Playing it back on another machine gives exactly the same run
+    let mut other = Cpu::new(AppleII::new());
+    let mut player = Player::new(movie);
+    assert!(player.start(&mut other).is_ok());
+    player.run(&mut other, cpu.cycles());
+    assert!(player.finished() && other.cycles() == cpu.cycles());
+    assert!(save_to_vec(&other) == save_to_vec(&cpu));
+}
+
+#[test]
+#[ignore]
+fn test_6502_functional()
+{
+    require_test_data(FUNCTIONAL_TEST);
+    let mut cpu = Cpu::new(load_flat_ram(FUNCTIONAL_TEST, 0x0000));
+    cpu.regs.pc = 0x0400;
+    let pc = run_until_trap(&mut cpu, 200*1000000);
+    if pc != FUNCTIONAL_TEST_SUCCESS {
+        fail!("functional test trapped at {:04x}, test case {:02x}", pc, cpu.mem.mem[0x0200]);
+    }
+}
+
+#[test]
+#[ignore]
+fn test_6502_decimal()
+{
+    require_test_data(DECIMAL_TEST);
+    let mut cpu = Cpu::new(load_flat_ram(DECIMAL_TEST, 0x0200));
+    cpu.regs.pc = 0x0200;
+    run_until_trap(&mut cpu, 200*1000000);
+    assert!(cpu.mem.mem[DECIMAL_TEST_ERROR] == 0);
+}
+
+// Flat 64K of RAM that also records every bus access as (address, value, is_write), in order.
+pub struct RecordingRam { mem: ~[u8], accesses: ~[(u16, u8, bool)] }
+
+impl RecordingRam {
+    fn new() -> RecordingRam {
+        RecordingRam { mem: vec::from_elem(0x10000, 0u8), accesses: ~[] }
+    }
+}
+
+impl Mem for RecordingRam {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        let val = self.mem[addr];
+        self.accesses.push((addr, val, false));
+        val
+    }
+    fn storeb(&mut self, addr: u16, val: u8) {
+        self.mem[addr] = val;
+        self.accesses.push((addr, val, true));
+    }
+}
+
+fn json_field<'a>(obj: &'a json::Object, key: &str) -> &'a Json {
+    match obj.find(&key.to_owned()) {
+        Some(val) => val,
+        None => fail!("missing field {}", key)
+    }
+}
+
+fn json_uint(val: &Json) -> uint {
+    match *val {
+        json::Number(n) => n as uint,
+        _ => fail!("expected a number")
+    }
+}
+
+fn json_list<'a>(val: &'a Json) -> &'a json::List {
+    match *val {
+        json::List(ref list) => list,
+        _ => fail!("expected a list")
+    }
+}
+
+fn json_object<'a>(val: &'a Json) -> &'a json::Object {
+    match *val {
+        json::Object(ref obj) => &**obj,
+        _ => fail!("expected an object")
+    }
+}
+
+fn json_regs(state: &json::Object) -> Regs {
+    Regs {
+        a: json_uint(json_field(state, "a")) as u8,
+        x: json_uint(json_field(state, "x")) as u8,
+        y: json_uint(json_field(state, "y")) as u8,
+        s: json_uint(json_field(state, "s")) as u8,
+        flags: json_uint(json_field(state, "p")) as u8,
+        pc: json_uint(json_field(state, "pc")) as u16,
+    }
+}
+
+// The "ram" field of a state is a list of [address, value] pairs.
+fn json_ram(state: &json::Object) -> ~[(u16, u8)] {
+    json_list(json_field(state, "ram")).iter().map(|pair| {
+        let pair = json_list(pair);
+        (json_uint(&pair[0]) as u16, json_uint(&pair[1]) as u8)
+    }).collect()
+}
+
+// Runs one single-step test case: sets up the initial registers and RAM, steps once, and checks
+// the final registers, RAM, and cycle count. With `check_bus`, the recorded bus accesses must
+// also match the expected per-cycle list exactly.
+fn run_single_step_case(case: &json::Object, check_bus: bool) {
+    let name = match *json_field(case, "name") {
+        json::String(ref name) => name.clone(),
+        _ => fail!("expected a name")
+    };
+    let initial = json_object(json_field(case, "initial"));
+    let expected = json_object(json_field(case, "final"));
+
+    let mut ram = RecordingRam::new();
+    for &(addr, val) in json_ram(initial).iter() {
+        ram.mem[addr] = val;
+    }
+    let mut cpu = Cpu::with_state(ram, Nmos6502, json_regs(initial), 0);
+    cpu.set_cycle_accurate(true);
+    cpu.step();
+
+    // Bits 4 and 5 of P don't exist in the register, so they're ignored.
+    let regs = cpu.regs();
+    let mut want = json_regs(expected);
+    want.flags = (want.flags & 0xcf) | (regs.flags & 0x30);
+    if regs != want {
+        fail!("{}: registers A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{:02X} PC:{:04X}",
+              name, regs.a, regs.x, regs.y, regs.s, regs.flags, regs.pc);
+    }
+    for &(addr, val) in json_ram(expected).iter() {
+        if cpu.mem.mem[addr] != val {
+            fail!("{}: ${:04X} is {:02X}, expected {:02X}", name, addr, cpu.mem.mem[addr], val);
+        }
+    }
+
+    let cycles = json_list(json_field(case, "cycles"));
+    if cpu.cycles() != cycles.len() as u64 {
+        fail!("{}: took {} cycles, expected {}", name, cpu.cycles(), cycles.len());
+    }
+    if check_bus {
+        let accesses: ~[(u16, u8, bool)] = cycles.iter().map(|cycle| {
+            let cycle = json_list(cycle);
+            let write = match cycle[2] {
+                json::String(ref kind) => kind.as_slice() == "write",
+                _ => fail!("expected a cycle kind")
+            };
+            (json_uint(&cycle[0]) as u16, json_uint(&cycle[1]) as u8, write)
+        }).collect();
+        if cpu.mem.accesses != accesses {
+            fail!("{}: bus accesses {:?}, expected {:?}", name, cpu.mem.accesses, accesses);
+        }
+    }
+}
+
+// The per-opcode 6502 test vectors from https://github.com/SingleStepTests/65x02, one
+// `<opcode>.json` file per opcode, each holding a list of test cases.
+static SINGLE_STEP_TESTS: &'static str = "65x02/6502/v1";
+
+// The JAMs and the unstable undocumented opcodes have no single right answer.
+static SINGLE_STEP_SKIPPED: [u8, ..19] = [
+    0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2,
+    0x8b, 0xab, 0x93, 0x9f, 0x9e, 0x9c, 0x9b,
+];
+
+#[test]
+#[ignore]
+fn test_single_step_6502()
+{
+    require_test_data(SINGLE_STEP_TESTS);
+    for op in range(0u, 0x100) {
+        if SINGLE_STEP_SKIPPED.iter().any(|&skipped| skipped as uint == op) {
+            continue;
+        }
+        let filename = format!("{}/{:02x}.json", SINGLE_STEP_TESTS, op);
+        let text = File::open(&Path::new(filename)).read_to_end();
+        let cases = match json::from_str(str::from_utf8(text)) {
+            Ok(cases) => cases,
+            Err(e) => fail!("{}: {}", filename, e.to_str())
+        };
+        for case in json_list(&cases).iter() {
+            run_single_step_case(json_object(case), true);
+        }
+    }
+}
+
+#[test]
+fn test_rom_identification()
+{
+    assert!(crc32(bytes!("123456789")) == 0xcbf43926);
+
+    // The controller's boot code skips a delay, calling JSR $FCA8 no longer, so it's not the
+    // dump until that's put back
+    let mut dc = DiskController::new();
+    let mut prom: ~[u8] = range(0u16, 0x100).map(|i| dc.doHighIO(0xc600 + i, 0)).collect();
+    assert!(identify(DiskBootRom, prom).is_none());
+    prom[0x4c] = 0x20;
+    prom[0x4d] = 0xa8;
+    prom[0x4e] = 0xfc;
+    assert!(identify(DiskBootRom, prom).unwrap().name == "Disk II 16-sector boot PROM (341-0027)");
+    assert!(identify(SystemRom, prom).is_none());
+
+    // and the real one can take its place
+    let name = identify(DiskBootRom, prom).unwrap().name.to_owned();
+    dc.load_boot_rom(prom, name);
+    assert!(dc.doHighIO(0xc64c, 0) == 0x20);
+    assert!(dc.rom_names() == ~[~"Disk II 16-sector boot PROM (341-0027)"]);
+}
+
+#[test]
+fn test_rom_search()
+{
+    // a directory of our own, so that other runs can't leave ROMs in it
+    let dir = os::tmpdir().join(format!("rustyapple-roms-{}", os::getpid()));
+    io::io_error::cond.trap(|_| {}).inside(|| fs::rmdir_recursive(&dir));
+    fs::mkdir(&dir, io::UserRWX);
+    let search = RomSearch::with_paths(~[dir.clone()]);
+    let err = search.load(SystemRom, ModelIIe).unwrap_err();
+    assert!(err.starts_with("no Apple //e system ROM found: looked for apple2e.rom in "));
+
+    // The wrong size is an error; the right size loads, whether or not it's known
+    File::create(&dir.join("apple2e.rom")).write([0u8, ..0x3000]);
+    let err = search.load(SystemRom, ModelIIe).unwrap_err();
+    assert!(err.ends_with("apple2e.rom is 12288 bytes, but the Apple //e system ROM is 16384"));
+    File::create(&dir.join("apple2e.rom")).write([0u8, ..0x4000]);
+    let rom = search.load(SystemRom, ModelIIe).unwrap();
+    assert!(rom.known.is_none() && rom.data.len() == 0x4000);
+
+    let mut a2 = AppleII::with_model(ModelIIe);
+    assert!(a2.read_roms(&search).is_ok());
+    assert!(a2.rom_name().starts_with("unrecognized system ROM, CRC-32 "));
+    let status = a2.status();
+    assert!(status[status.len() - 1].starts_with("Apple //e: unrecognized"));
+    assert!(a2.rom_names().len() == 1 && a2.character_rom().is_none());
+
+    // A character ROM is optional, but checked and listed when there is one
+    File::create(&dir.join("apple2e.chr")).write([0u8, ..0x800]);
+    assert!(AppleII::with_model(ModelIIe).read_roms(&search).is_err());
+    File::create(&dir.join("apple2e.chr")).write([0u8, ..0x1000]);
+    let mut a2 = AppleII::with_model(ModelIIe);
+    assert!(a2.read_roms(&search).is_ok());
+    assert!(a2.character_rom().unwrap().len() == 0x1000);
+    let names = a2.rom_names();
+    assert!(names.len() == 2 && names[1].starts_with("unrecognized character ROM, CRC-32 "));
+    let status = a2.status();
+    assert!(status[status.len() - 2].starts_with("ROM: unrecognized character ROM"));
+
+    fs::rmdir_recursive(&dir);
+}
+
 #[test]
 fn test_a2()
 {
     let mut a2 = AppleII::new();
-    a2.read_roms();
+    a2.read_roms(&RomSearch::new()).unwrap();
     let mut dc: DiskController = DiskController::new();
     dc.load_disk(0, "JUNK4.DSK");
     assert!(dc.has_disk(0));
     a2.set_slot(6, ~dc);
     let mut cpu = Cpu::new(a2);
     cpu.reset();
-    for i in range(0,10*1000000) {
-        cpu.step();
-    }
+    assert!(cpu.run_cycles(30*1000000) == RanCycles);
 }
 
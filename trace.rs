@@ -0,0 +1,228 @@
+//
+// Execution trace recorder
+//
+// A `Tracer` attached to the CPU records every instruction it executes: the PC, registers and
+// cycle count before it ran, and each bus access it made. Records pass through a filter, then go
+// to a ring buffer holding the most recent ones, a binary log, or both.
+//
+// The binary log starts with `TRACE_MAGIC` and a version byte. Each record is then:
+//
+//   accesses  u8       how many bus accesses follow the registers
+//   cycle     u64 LE
+//   pc        u16 LE
+//   a x y p s u8 each
+//   then per access: addr u16 LE, value u8, 1 if a write else 0
+//
+// The opcode isn't stored separately, since it's always the value of the first access.
+//
+
+use extra::ringbuf::RingBuf;
+use extra::container::Deque;
+use std::io::{Reader, Writer};
+use cpu::{Cycles, Regs, CpuVariant};
+use debugger::{SOFT_SWITCH_LO, SOFT_SWITCH_HI};
+use disasm::Disassembler;
+use mem::{Mem, BusAccess};
+
+pub static TRACE_MAGIC: &'static [u8] = bytes!("A2TRACE");
+pub static TRACE_VERSION: u8 = 1;
+
+/// One executed instruction.
+#[deriving(Eq, Clone)]
+pub struct TraceRecord {
+    /// The cycle the instruction started on.
+    pub cy: Cycles,
+    /// The registers before it ran.
+    pub regs: Regs,
+    /// Its bus accesses in order, starting with the opcode fetch.
+    pub accesses: ~[BusAccess],
+}
+
+/// Which instructions get recorded.
+#[deriving(Eq, Clone)]
+pub struct TraceFilter {
+    /// Only instructions at `pc_lo..pc_hi` (inclusive).
+    pub pc_lo: u16,
+    pub pc_hi: u16,
+    /// Only instructions that touch a soft switch.
+    pub soft_switches: bool,
+}
+
+impl TraceFilter {
+    /// Lets every instruction through.
+    pub fn all() -> TraceFilter {
+        TraceFilter { pc_lo: 0, pc_hi: 0xffff, soft_switches: false }
+    }
+
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        let pc = record.regs.pc;
+        pc >= self.pc_lo && pc <= self.pc_hi &&
+            (!self.soft_switches || record.accesses.iter().any(|access| {
+                access.addr >= SOFT_SWITCH_LO && access.addr <= SOFT_SWITCH_HI
+            }))
+    }
+}
+
+impl TraceRecord {
+    /// The instruction's opcode.
+    pub fn opcode(&self) -> u8 {
+        if self.accesses.len() > 0 { self.accesses[0].val } else { 0 }
+    }
+
+    pub fn write_to(&self, w: &mut Writer) {
+        w.write_u8(self.accesses.len() as u8);
+        w.write_le_u64(self.cy);
+        w.write_le_u16(self.regs.pc);
+        w.write([self.regs.a, self.regs.x, self.regs.y, self.regs.flags, self.regs.s]);
+        for access in self.accesses.iter() {
+            w.write_le_u16(access.addr);
+            w.write([access.val, access.write as u8]);
+        }
+    }
+
+    /// Reads the next record, or None at the end of the log.
+    pub fn read_from(r: &mut Reader) -> Option<TraceRecord> {
+        let count = match r.read_byte() {
+            Some(count) => count,
+            None => return None
+        };
+        let cy = r.read_le_u64();
+        let pc = r.read_le_u16();
+        let b = r.read_bytes(5);
+        let regs = Regs { a: b[0], x: b[1], y: b[2], flags: b[3], s: b[4], pc: pc };
+        let accesses = range(0, count).map(|_| {
+            let addr = r.read_le_u16();
+            let b = r.read_bytes(2);
+            BusAccess { addr: addr, val: b[0], write: b[1] != 0 }
+        }).collect();
+        Some(TraceRecord { cy: cy, regs: regs, accesses: accesses })
+    }
+
+    /// Renders the record in the style of the nestest log:
+    /// `C000  A9 00     LDA #$00       A:00 X:00 Y:00 P:24 SP:FD CYC:7`.
+    pub fn to_text(&self, variant: CpuVariant) -> ~str {
+        let mut fetched = FetchedBytes { record: self };
+        let line = Disassembler::with_variant(self.regs.pc, &mut fetched, variant)
+                       .disassemble_line();
+        let r = &self.regs;
+        format!("{:<30s} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:u}",
+                line, r.a, r.x, r.y, r.flags, r.s, self.cy)
+    }
+}
+
+// The bytes an instruction fetched, as memory for the disassembler. Everything the instruction
+// didn't read is zero.
+struct FetchedBytes<'a> {
+    record: &'a TraceRecord,
+}
+
+impl<'a> Mem for FetchedBytes<'a> {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        match self.record.accesses.iter().find(|access| access.addr == addr && !access.write) {
+            Some(access) => access.val,
+            None => 0
+        }
+    }
+    fn storeb(&mut self, _: u16, _: u8) {}
+}
+
+pub struct Tracer {
+    filter: TraceFilter,
+    ring: RingBuf<TraceRecord>,
+    capacity: uint,
+    log: Option<~Writer>,
+    current: Option<TraceRecord>,
+}
+
+impl Tracer {
+    /// Keeps the last `capacity` records in memory.
+    pub fn new(capacity: uint) -> Tracer {
+        Tracer {
+            filter: TraceFilter::all(),
+            ring: RingBuf::new(),
+            capacity: capacity,
+            log: None,
+            current: None,
+        }
+    }
+
+    /// Writes every record to a binary log, as well as keeping the last `capacity` in memory,
+    /// which may be none.
+    pub fn with_log(capacity: uint, log: ~Writer) -> Tracer {
+        let mut log = log;
+        log.write(TRACE_MAGIC);
+        log.write_u8(TRACE_VERSION);
+        let mut tracer = Tracer::new(capacity);
+        tracer.log = Some(log);
+        tracer
+    }
+
+    pub fn filter(&self) -> TraceFilter { self.filter.clone() }
+    pub fn set_filter(&mut self, filter: TraceFilter) { self.filter = filter }
+
+    /// The records in the ring buffer, oldest first.
+    pub fn records(&self) -> ~[TraceRecord] {
+        self.ring.iter().map(|record| record.clone()).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.ring.clear();
+    }
+
+    //
+    // Called by the CPU
+    //
+
+    /// An instruction is starting.
+    pub fn begin(&mut self, regs: &Regs, cy: Cycles) {
+        self.current = Some(TraceRecord { cy: cy, regs: regs.clone(), accesses: ~[] });
+    }
+
+    /// A bus access by the current instruction.
+    pub fn access(&mut self, access: BusAccess) {
+        match self.current {
+            Some(ref mut record) => record.accesses.push(access),
+            None => {}
+        }
+    }
+
+    /// The current instruction has finished.
+    pub fn end(&mut self) {
+        let record = match self.current.take() {
+            Some(record) => record,
+            None => return
+        };
+        if !self.filter.matches(&record) {
+            return;
+        }
+        match self.log {
+            Some(ref mut log) => record.write_to(&mut **log),
+            None => {}
+        }
+        if self.capacity > 0 {
+            if self.ring.len() == self.capacity {
+                self.ring.pop_front();
+            }
+            self.ring.push_back(record);
+        }
+    }
+}
+
+/// Reads a whole binary log, failing if it doesn't start with the magic and a version this
+/// understands.
+pub fn read_log(r: &mut Reader) -> ~[TraceRecord] {
+    if r.read_bytes(TRACE_MAGIC.len()).as_slice() != TRACE_MAGIC {
+        fail!("not a trace log");
+    }
+    let version = r.read_u8();
+    if version != TRACE_VERSION {
+        fail!("unsupported trace log version {}", version);
+    }
+    let mut records = ~[];
+    loop {
+        match TraceRecord::read_from(r) {
+            Some(record) => records.push(record),
+            None => return records
+        }
+    }
+}
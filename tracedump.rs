@@ -0,0 +1,45 @@
+//
+// tracedump: prints a binary trace log in the style of the nestest log
+//
+//   tracedump [--65c02] trace.log
+//
+
+#[feature(macro_rules)];
+
+extern mod extra;
+
+use std::io::File;
+use std::io::buffered::BufferedReader;
+use std::os;
+use cpu::{Nmos6502, Cmos65C02};
+use trace::read_log;
+
+#[macro_escape]
+pub mod util;
+
+#[macro_escape]
+pub mod cpu;
+pub mod disasm;
+pub mod debugger;
+pub mod state;
+pub mod rewind;
+pub mod trace;
+pub mod mem;
+
+fn main()
+{
+    let args = os::args();
+    let (variant, path) = match args.len() {
+        2 => (Nmos6502, args[1].clone()),
+        3 if args[1] == ~"--65c02" => (Cmos65C02, args[2].clone()),
+        _ => {
+            println("usage: tracedump [--65c02] trace.log");
+            return;
+        }
+    };
+    let file = File::open(&Path::new(path)).expect("can't open the trace log");
+    let mut reader = BufferedReader::new(file);
+    for record in read_log(&mut reader).iter() {
+        println(record.to_text(variant));
+    }
+}
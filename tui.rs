@@ -1,11 +1,30 @@
 
 #[feature(link_args, macro_rules)];
 
+extern mod extra;
+
+use std::cmp::min;
+use std::io;
+use std::io::Timer;
+use std::io::{Listener, Acceptor};
+use std::io::net::ip::{SocketAddr, Ipv4Addr};
+use std::io::net::tcp::TcpListener;
+use std::os;
+use std::io::File;
+use std::io::buffered::BufferedWriter;
+use std::run;
 use cpu::Cpu;
 use mem::Mem;
-use a2::AppleII;
+use a2::{AppleII, Model, ModelIIPlus, CYCLES_PER_FRAME};
 use a2::Peripheral;
 use diskii::DiskController;
+use debugger::{Debugger, DebugEvent, OutOfCycles};
+use monitor::{Monitor, Print, Go, Quit, describe_event};
+use gdbstub::GdbStub;
+use trace::Tracer;
+use state::read_save_file;
+use roms::{RomSearch, DiskBootRom, DiskSequencerRom, load_file};
+use movie::{Movie, Player, InputEvent, KeyPress};
 use util::current_time_millis;
 use lazyterm::{Terminal,Buffer};
 
@@ -15,9 +34,18 @@ pub mod util;
 
 #[macro_escape]
 pub mod cpu;
+pub mod disasm;
+pub mod trace;
+pub mod debugger;
+pub mod state;
+pub mod rewind;
+pub mod movie;
+pub mod monitor;
+pub mod gdbstub;
 pub mod mem;
 pub mod a2;
 pub mod diskii;
+pub mod roms;
 
 pub mod lazyterm;
 
@@ -29,13 +57,31 @@ static text_lut: [u16, ..8*3] = [
 
 static flashInterval: u64 = 500;
 
+// the monitor console goes under the screen
+static CONSOLE_ROWS: uint = 8;
+// how many lines of output the console keeps
+static CONSOLE_SCROLLBACK: uint = 200;
+// Ctrl-backslash
+static BREAK_KEY: u8 = 0x1c;
+
+// the machine runs in whole frames, so how fast the host is doesn't change what it does
+static FRAME_MSEC: u64 = 1000 / 60;
+
 fn draw_text_line(a2: &AppleII, buf: &mut Buffer, flash: bool, y: uint)
 {
   // get the base address of this line
-  let base = text_lut[y] + if (a2.grswitch & a2::GR_PAGE1) != 0 { 0x800 } else { 0x400 };
-  for x in range(0u,40)
+  let base = text_lut[y] + if a2.display_page2() { 0x800 } else { 0x400 };
+  // in 80 columns, the even columns come from aux memory
+  let main = a2.mem();
+  let col80 = if a2.col80() { a2.aux_mem() } else { None };
+  let width = if col80.is_some() { 80 } else { 40 };
+  for x in range(0u,width)
   {
-     let mut b = a2.mem[base + x as u16];
+     let mut b = match col80 {
+       Some(aux) if x % 2 == 0 => aux[base + (x/2) as u16],
+       Some(_) => main[base + (x/2) as u16],
+       None => main[base + x as u16]
+     };
      let invert: bool;
      // invert flash characters 1/2 of the time
      if (b >= 0x80)
@@ -58,9 +104,14 @@ fn draw_text_line(a2: &AppleII, buf: &mut Buffer, flash: bool, y: uint)
      } else {
        lazyterm::TermCell { bg:lazyterm::WHITE, fg:lazyterm::BLACK, ch:ch }
      };
-     buf.set(x*2, y, cell);
-     cell.ch = ' ';
-     buf.set(x*2+1, y, cell);
+     if col80.is_some()
+     {
+        buf.set(x, y, cell);
+     } else {
+        buf.set(x*2, y, cell);
+        cell.ch = ' ';
+        buf.set(x*2+1, y, cell);
+     }
   }
 }
 
@@ -72,38 +123,264 @@ fn update_term_buf(a2: &AppleII, buf: &mut Buffer, flash: bool)
   }
 }
 
+fn draw_console(buf: &mut Buffer, lines: &[~str], prompt: &str)
+{
+  // the last lines that fit, then the prompt
+  let start = lines.len() - min(lines.len(), CONSOLE_ROWS - 1);
+  let mut rows: ~[&str] = lines.slice_from(start).iter().map(|l| l.as_slice()).collect();
+  rows.push(prompt);
+  for y in range(0u, CONSOLE_ROWS)
+  {
+     let text: ~[char] = if y < rows.len() { rows[y].chars().collect() } else { ~[] };
+     for x in range(0u, buf.width)
+     {
+        let ch = if x < text.len() { text[x] } else { ' ' };
+        buf.set(x, 24 + y, lazyterm::TermCell { bg:lazyterm::BLACK, fg:lazyterm::WHITE, ch:ch });
+     }
+  }
+}
+
+// adds lines to the console, forgetting the oldest beyond the scrollback
+fn console_print(console: &mut ~[~str], lines: ~[~str])
+{
+  console.push_all_move(lines);
+  if console.len() > CONSOLE_SCROLLBACK
+  {
+    let kept = console.slice_from(console.len() - CONSOLE_SCROLLBACK).to_owned();
+    *console = kept;
+  }
+}
+
+// reads stdin on its own task, so the main loop can poll for keys
+fn spawn_key_reader() -> Port<u8>
+{
+  let (port, chan) = Chan::new();
+  spawn(proc() {
+    let mut stdin = io::stdin();
+    loop
+    {
+      match stdin.read_byte() {
+        Some(b) => chan.send(b),
+        None => break
+      }
+    }
+  });
+  port
+}
+
+fn set_raw_mode(raw: bool)
+{
+  let args = if raw { [~"raw", ~"-echo"] } else { [~"-raw", ~"echo"] };
+  run::process_status("stty", args);
+}
+
+// Waits for GDB to attach on a local port, and serves it until it detaches.
+fn serve_gdb(dbg: &mut Debugger<AppleII>, port: u16)
+{
+  let addr = SocketAddr { ip: Ipv4Addr(127, 0, 0, 1), port: port };
+  let mut acceptor = TcpListener::bind(addr).expect("can't bind the gdb port").listen().unwrap();
+  println!("waiting for gdb on port {}", port);
+  let mut stream = acceptor.accept().unwrap();
+  // a continue gives control back to gdb after a second
+  GdbStub::new(1000000).serve(dbg, &mut stream);
+}
+
+// feeds input to the machine, recording it if there's a movie being made
+fn feed_input(dbg: &mut Debugger<AppleII>, recording: &mut Option<Movie>, event: InputEvent)
+{
+  match *recording {
+    Some(ref mut movie) => movie.input(dbg.cpu_mut(), event),
+    None => event.apply(dbg.cpu_mut().mem_mut())
+  }
+}
+
+// runs to the end of the frame, stopping on the way to deliver a movie's input on time
+fn run_frame(dbg: &mut Debugger<AppleII>, player: &mut Option<Player>) -> DebugEvent
+{
+  let end = (dbg.cpu().cycles() / CYCLES_PER_FRAME + 1) * CYCLES_PER_FRAME;
+  loop
+  {
+    let mut budget = end - dbg.cpu().cycles();
+    for p in player.mut_iter()
+    {
+      p.deliver(dbg.cpu_mut());
+      for next in p.cycles_to_next(dbg.cpu()).iter() { budget = min(budget, *next); }
+    }
+    let event = dbg.go(budget);
+    if event != OutOfCycles || dbg.cpu().cycles() >= end { return event; }
+  }
+}
+
+// the value of an option that's needed before the machine is built
+fn early_option(args: &[~str], name: &str) -> Option<~str>
+{
+  match args.iter().position(|arg| arg.as_slice() == name) {
+    Some(i) if i + 1 < args.len() => Some(args[i+1].clone()),
+    _ => None
+  }
+}
+
 fn main()
 {
-    let mut a2 = AppleII::new();
-    a2.read_roms();
+    // --model ii, ii+, iie or enhanced-iie picks the machine, a II+ by default;
+    // --roms DIR looks for ROMs there before the current directory and ./roms
+    let args = os::args();
+    let model: Model = match early_option(args, "--model") {
+      Some(name) => from_str(name).expect("unknown model"),
+      None => ModelIIPlus
+    };
+    let mut search = RomSearch::new();
+    for dir in early_option(args, "--roms").iter() {
+      search.paths.unshift(Path::new(dir.as_slice()));
+    }
+    let mut a2 = AppleII::with_model(model);
+    match a2.read_roms(&search) {
+      Ok(()) => (),
+      Err(e) => fail!(e)
+    }
     let mut dc: DiskController = DiskController::new();
+    // without a dump of the Disk II's boot PROM, the built-in one will do
+    for path in search.find(DiskBootRom, model).move_iter() {
+      match load_file(DiskBootRom, model, path) {
+        Ok(rom) => dc.load_boot_rom(rom.data, rom.describe()),
+        Err(e) => fail!(e)
+      }
+    }
+    // nor is the sequencer emulated from its PROM, but it's listed with the machine's ROMs
+    for path in search.find(DiskSequencerRom, model).move_iter() {
+      match load_file(DiskSequencerRom, model, path) {
+        Ok(rom) => dc.load_sequencer_rom(rom.data, rom.describe()),
+        Err(e) => fail!(e)
+      }
+    }
     dc.load_disk(0, "JUNK4.DSK");
     assert!(dc.has_disk(0));
     a2.set_slot(6, ~dc);
-    let mut cpu = Cpu::new(a2);
+    let mut cpu = Cpu::with_variant(a2, model.cpu_variant());
     cpu.reset();
+    let mut dbg = Debugger::new(cpu);
+    // about ten seconds of history for the monitor to step back through
+    dbg.enable_rewind(100000, 100);
+    // --load FILE starts from a save file; --trace FILE logs every instruction;
+    // --gdb PORT waits for GDB before starting; --record FILE writes a movie of the session
+    // on quitting; --play FILE plays one back, ignoring the keyboard
+    let mut record_path = None;
+    let mut player = None;
+    let mut i = 1;
+    while i + 1 < args.len()
+    {
+      let arg = args[i+1].clone();
+      match args[i].as_slice() {
+        "--gdb" => serve_gdb(&mut dbg, from_str::<u16>(arg).expect("bad gdb port")),
+        "--trace" => {
+          let file = File::create(&Path::new(arg)).expect("can't create the trace log");
+          let log = ~BufferedWriter::new(file) as ~Writer;
+          dbg.cpu_mut().set_tracer(Some(Tracer::with_log(0, log)));
+        }
+        "--load" => {
+          let mut file = File::open(&Path::new(arg)).expect("can't open the save file");
+          let model = dbg.cpu().mem().model_name();
+          match read_save_file(&mut file, model, dbg.cpu_mut()) {
+            Ok(()) => (),
+            Err(e) => fail!(e)
+          }
+        }
+        "--model" | "--roms" => (),
+        "--record" => { record_path = Some(Path::new(arg)); }
+        "--play" => {
+          let mut file = File::open(&Path::new(arg)).expect("can't open the movie");
+          let model = dbg.cpu().mem().model_name();
+          let mut p = match Movie::read_from(&mut file, model) {
+            Ok(movie) => Player::new(movie),
+            Err(e) => fail!(e)
+          };
+          match p.start(dbg.cpu_mut()) {
+            Ok(()) => (),
+            Err(e) => fail!(e)
+          }
+          dbg.clear_rewind();
+          player = Some(p);
+        }
+        _ => fail!("unknown option {}", args[i])
+      }
+      i += 2;
+    }
+    let mut recording = if record_path.is_some() { Some(Movie::record(dbg.cpu())) } else { None };
+    let mut monitor = Monitor::new();
+    monitor.set_recording(recording.is_some());
+    let mut console: ~[~str] = ~[~"CTRL-\\ ENTERS THE MONITOR, H FOR HELP"];
+    let mut input = ~"";
+    let mut in_monitor = false;
     
     let mut term = Terminal::new();
-    let mut buf = Buffer::new(80,24);
+    let mut buf = Buffer::new(80,24+CONSOLE_ROWS);
+    let mut timer = Timer::new().unwrap();
+    let keys = spawn_key_reader();
+    set_raw_mode(true);
     
-    // mismatched types: expected `<generic integer #5>` but found `<generic float #0>`
     let speedup = 2;
-    let clocks_per_msec = (1000 * speedup);
-    let mut t0 = current_time_millis();
     loop
     {
+        loop
+        {
+          match keys.try_recv() {
+            None => break,
+            Some(BREAK_KEY) => { in_monitor = true; }
+            Some(key) if in_monitor => match key {
+              0x0d | 0x0a => {
+                console_print(&mut console, ~[format!("*{}", input)]);
+                match monitor.execute(&mut dbg, input) {
+                  Print(lines) => console_print(&mut console, lines),
+                  Go => { in_monitor = false; }
+                  Quit => {
+                    set_raw_mode(false);
+                    for (path, movie) in record_path.iter().zip(recording.iter()) {
+                      let mut file = File::create(path).expect("can't create the movie");
+                      movie.write_to(&mut file, dbg.cpu().mem().model_name());
+                    }
+                    return;
+                  }
+                }
+                input = ~"";
+              }
+              0x08 | 0x7f => { if input.len() > 0 { input.pop_char(); } }
+              _ => input.push_char(key as char)
+            },
+            // a movie's input is all it gets
+            Some(_) if player.is_some() => (),
+            // DEL is the left arrow
+            Some(key) => {
+              let key = if key == 0x7f { 0x08 } else { key };
+              feed_input(&mut dbg, &mut recording, KeyPress(key));
+            }
+          }
+        }
+
         // cursor flashing?
+        let t0 = current_time_millis();
         let flash = (t0 % (flashInterval<<1)) > flashInterval;
-        update_term_buf(&cpu.mem, &mut buf, flash);
+        update_term_buf(dbg.cpu().mem(), &mut buf, flash);
+        draw_console(&mut buf, console, if in_monitor { format!("*{}", input) } else { ~"" });
         term.refresh(&buf);
         
-        let t1 = current_time_millis();
-        let cycle = cpu.cy + (t1-t0)*clocks_per_msec;
-        while cpu.cy < cycle
+        if in_monitor
         {
-          cpu.step();
+          timer.sleep(20);
+        } else {
+          for _ in range(0, speedup)
+          {
+            let event = run_frame(&mut dbg, &mut player);
+            if event != OutOfCycles
+            {
+              console_print(&mut console, ~[describe_event(&event)]);
+              in_monitor = true;
+              break;
+            }
+          }
+          // keep to the frame rate, allowing for the time taken to run and draw
+          let elapsed = current_time_millis() - t0;
+          if elapsed < FRAME_MSEC { timer.sleep(FRAME_MSEC - elapsed); }
         }
-        t0 = t1;
     }
 }
 